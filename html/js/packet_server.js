@@ -33,6 +33,8 @@ class CircularBuffer {
 
 var buffer = new CircularBuffer();
 var ips = new Map();
+// server names seen in tls ClientHellos
+var sni_names = new Map();
 var local_ips = new Set();
 
 var query_callbacks = new Map();
@@ -52,7 +54,7 @@ function connect_packet_server(handler) {
 
                 switch (type) {
                     case 'lookup_addr':
-                        request_once_handler('lookup' + data.ip, data.hostname);
+                        notify_once('lookup' + data.ip, data);
                         ips.set(data.ip, data.hostname);
                         break;
                     case 'local_addr':
@@ -65,6 +67,50 @@ function connect_packet_server(handler) {
                     case 'geoip':
                         notify_once('geoip' + data.ip, data);
                         break;
+                    case 'fragment_stats':
+                        notify_once('fragment_stats', data.stats);
+                        break;
+                    case 'flows':
+                        notify_once('flows', data.flows);
+                        break;
+                    case 'tcp_connections':
+                        notify_once('tcp_connections', data.connections);
+                        break;
+                    case 'tls_handshakes':
+                        notify_once('tls_handshakes', data.handshakes);
+                        break;
+                    case 'dns_cache':
+                        notify_once('dns_cache', data.cache);
+                        break;
+                    case 'dns_cache_lookup':
+                        notify_once('dns_cache_lookup' + data.ip, data.names);
+                        break;
+                    case 'dns_resolvers':
+                        notify_once('dns_resolvers', data.resolvers);
+                        break;
+                    case 'tls_stats':
+                        notify_once('tls_stats', data.stats);
+                        break;
+                    case 'tls_alerts':
+                        notify_once('tls_alerts', data.alerts);
+                        break;
+                    case 'tls_handshake':
+                        // label servers by the name the client asked for
+                        if (data.sni && data.server) {
+                            sni_names.set(data.server, data.sni);
+                        }
+                        break;
+                    case 'flow_start':
+                    case 'flow_end':
+                    case 'tcp_state':
+                    case 'tcp_anomaly':
+                    case 'tls_fingerprint':
+                    case 'tls_alert':
+                    case 'tls_anomaly':
+                    case 'dns_response':
+                    case 'dns_timeout':
+                        // subscribers were notified above
+                        break;
                     default:
                         console.log(data);
                 }
@@ -111,6 +157,51 @@ function query_geo_ip(ip, cb) {
     query({req: 'geoip', value: ip, type: ''});
 }
 
+function query_fragment_stats(cb) {
+    request_once_handler('fragment_stats', cb);
+    query({req: 'fragment_stats', value: '', type: ''});
+}
+
+function query_flows(cb) {
+    request_once_handler('flows', cb);
+    query({req: 'flows', value: '', type: ''});
+}
+
+function query_tcp_connections(cb) {
+    request_once_handler('tcp_connections', cb);
+    query({req: 'tcp_connections', value: '', type: ''});
+}
+
+function query_tls_handshakes(cb) {
+    request_once_handler('tls_handshakes', cb);
+    query({req: 'tls_handshakes', value: '', type: ''});
+}
+
+function query_dns_cache(cb) {
+    request_once_handler('dns_cache', cb);
+    query({req: 'dns_cache', value: '', type: ''});
+}
+
+function query_dns_cache_lookup(ip, cb) {
+    request_once_handler('dns_cache_lookup' + ip, cb);
+    query({req: 'dns_cache_lookup', value: ip, type: ''});
+}
+
+function query_dns_resolvers(cb) {
+    request_once_handler('dns_resolvers', cb);
+    query({req: 'dns_resolvers', value: '', type: ''});
+}
+
+function query_tls_stats(cb) {
+    request_once_handler('tls_stats', cb);
+    query({req: 'tls_stats', value: '', type: ''});
+}
+
+function query_tls_alerts(cb) {
+    request_once_handler('tls_alerts', cb);
+    query({req: 'tls_alerts', value: '', type: ''});
+}
+
 /* pub sub system */
 function subscribe(topic, handler) {
     if (!topic_subscribers.has(topic)) {
@@ -168,5 +259,5 @@ function is_local(ip) {
 }
 
 function lookup(ip) {
-    return ips.get(ip);
+    return sni_names.get(ip) || ips.get(ip);
 }
\ No newline at end of file
@@ -8,6 +8,14 @@ use std::thread;
 
 use serde_json::json;
 
+use super::dns_cache::{cached_names, dump_cache};
+use super::dns_stats::resolver_stats;
+use super::flows::current_flows;
+use super::fragments::fragment_stats;
+use super::tcp::current_handshakes;
+use super::tcp_state::current_connections;
+use super::tls_alert::alert_summaries;
+use super::tls_stats::tls_stats;
 use super::traceroute;
 use super::{asn_lookup, city_lookup};
 use super::{parse_dns, reverse_lookup};
@@ -56,12 +64,19 @@ pub fn handle_clients(
                             "lookup" => {
                                 // handle look up address
                                 let ip = data.value;
-                                let hostname = reverse_lookup(ip.clone());
+                                let hostname = match ip.parse() {
+                                    Ok(addr) => reverse_lookup(addr),
+                                    Err(e) => {
+                                        println!("Can't parse ip {}, {}", ip, e);
+                                        None
+                                    }
+                                };
                                 // println!("Name look up from: {} to {}", destination, hostname);
 
                                 let p = json!({
                                     "type": "lookup_addr",
                                     "ip": ip,
+                                    "status": if hostname.is_some() { "resolved" } else { "unresolved" },
                                     "hostname": hostname,
                                 })
                                 .to_string();
@@ -100,6 +115,97 @@ pub fn handle_clients(
                                     }
                                 };
                             }
+                            "fragment_stats" => {
+                                let p = json!({
+                                    "type": "fragment_stats",
+                                    "stats": fragment_stats(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "flows" => {
+                                let p = json!({
+                                    "type": "flows",
+                                    "flows": current_flows(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "tcp_connections" => {
+                                let p = json!({
+                                    "type": "tcp_connections",
+                                    "connections": current_connections(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "tls_handshakes" => {
+                                let p = json!({
+                                    "type": "tls_handshakes",
+                                    "handshakes": current_handshakes(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "dns_cache" => {
+                                let p = json!({
+                                    "type": "dns_cache",
+                                    "cache": dump_cache(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "dns_cache_lookup" => {
+                                let ip = data.value;
+                                let names = match ip.parse() {
+                                    Ok(addr) => cached_names(&addr),
+                                    Err(e) => {
+                                        println!("Can't parse ip {}, {}", ip, e);
+                                        Vec::new()
+                                    }
+                                };
+
+                                let p = json!({
+                                    "type": "dns_cache_lookup",
+                                    "ip": ip,
+                                    "names": names,
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "dns_resolvers" => {
+                                let p = json!({
+                                    "type": "dns_resolvers",
+                                    "resolvers": resolver_stats(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "tls_stats" => {
+                                let p = json!({
+                                    "type": "tls_stats",
+                                    "stats": tls_stats(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
+                            "tls_alerts" => {
+                                let p = json!({
+                                    "type": "tls_alerts",
+                                    "alerts": alert_summaries(),
+                                })
+                                .to_string();
+
+                                broadcast(clients.clone(), p);
+                            }
                             "geoip" => {
                                 let ip = data.value;
                                 if let Some(r) = get_geo_ip(ip) {
@@ -128,7 +234,7 @@ fn broadcast(clients: Arc<RwLock<Vec<Writer<TcpStream>>>>, text: String) {
     clients
         .write()
         .unwrap()
-        .drain_filter(|c| c.send_message(&message).is_err());
+        .retain_mut(|c| c.send_message(&message).is_ok());
 }
 
 fn get_geo_ip(ip: String) -> Option<String> {
@@ -0,0 +1,450 @@
+use clap::{App, Arg, ArgMatches};
+use serde::Deserialize;
+
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+use super::recorder::RecorderOptions;
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load();
+}
+
+/**
+ * Runtime configuration, built from defaults, an optional
+ * TOML/JSON config file and command line flags (in that order)
+ */
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // libpcap, supports bpf filters and snaplen
+    Pcap,
+    // pnet datalink channel
+    Pnet,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Dissectors {
+    pub tcp: bool,
+    pub udp: bool,
+    pub icmp: bool,
+    pub dns: bool,
+    pub tls: bool,
+    // gre, ip-in-ip, vxlan and geneve decapsulation
+    pub tunnels: bool,
+}
+
+impl Default for Dissectors {
+    fn default() -> Dissectors {
+        Dissectors {
+            tcp: true,
+            udp: true,
+            icmp: true,
+            dns: true,
+            tls: true,
+            tunnels: true,
+        }
+    }
+}
+
+impl Dissectors {
+    fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match name {
+            "tcp" => self.tcp = enabled,
+            "udp" => self.udp = enabled,
+            "icmp" => self.icmp = enabled,
+            "dns" => self.dns = enabled,
+            "tls" => self.tls = enabled,
+            "tunnels" => self.tunnels = enabled,
+            _ => return Err(format!("Unknown dissector {}", name)),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    // capture devices, each captured on its own thread. defaults to pcap's lookup
+    pub interfaces: Vec<String>,
+    // replay a .pcap/.pcapng file instead of capturing live
+    pub read_file: Option<String>,
+    // replay pacing, 1.0 is real-time, 0 is as fast as possible
+    pub replay_speed: f64,
+    // write raw frames into rotating pcap files in this directory
+    pub record_dir: Option<String>,
+    // rotate recordings after this many megabytes
+    pub record_size_mb: u64,
+    // rotate recordings after this many seconds, 0 disables
+    pub record_interval: u64,
+    // number of recordings to keep
+    pub record_files: usize,
+    pub backend: Backend,
+    pub promisc: bool,
+    pub snaplen: i32,
+    // read timeout in ms
+    pub timeout: i32,
+    // bpf filter, eg. "tcp port 443"
+    pub filter: Option<String>,
+    // websocket server address
+    pub bind: String,
+    pub geoip_city: String,
+    pub geoip_asn: String,
+    pub dissectors: Dissectors,
+    // flows without packets for this many seconds end
+    pub flow_idle_timeout: u64,
+    // long lived flows are reported and restarted after this many seconds
+    pub flow_active_timeout: u64,
+    pub debug: bool,
+    pub stats: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interfaces: Vec::new(),
+            read_file: None,
+            replay_speed: 1.0,
+            record_dir: None,
+            record_size_mb: 100,
+            record_interval: 0,
+            record_files: 10,
+            backend: Backend::Pnet,
+            promisc: true,
+            snaplen: 65535,
+            timeout: 1,
+            filter: None,
+            bind: "127.0.0.1:3012".to_owned(),
+            geoip_city: "geodata/mmdb/GeoLite2-City.mmdb".to_owned(),
+            geoip_asn: "geodata/mmdb/GeoLite2-ASN.mmdb".to_owned(),
+            dissectors: Default::default(),
+            flow_idle_timeout: 60,
+            flow_active_timeout: 1800,
+            debug: false,
+            stats: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parses command line arguments, exits the process on bad configuration
+    pub fn load() -> Config {
+        let matches = app().get_matches();
+
+        let mut config = match matches.value_of("config") {
+            Some(path) => Config::from_file(path).unwrap_or_else(|e| {
+                eprintln!("Can't load config {}: {}", path, e);
+                process::exit(1);
+            }),
+            None => Config::default(),
+        };
+
+        if let Err(e) = config.apply_args(&matches) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+
+        config
+    }
+
+    pub fn recorder_options(&self) -> RecorderOptions {
+        RecorderOptions {
+            max_bytes: self.record_size_mb * 1024 * 1024,
+            max_age: match self.record_interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_files: self.record_files,
+            snaplen: self.snaplen as u32,
+        }
+    }
+
+    /// Reads a .json or .toml config file
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        if path.ends_with(".json") {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&contents).map_err(|e| e.to_string())
+        }
+    }
+
+    fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        // keep supporting `packet_radar 127.0.0.1:3012`
+        if let Some(bind) = matches.value_of("bind").or(matches.value_of("BIND")) {
+            self.bind = bind.to_owned();
+        }
+
+        if let Some(interfaces) = matches.values_of("interface") {
+            self.interfaces = interfaces.map(|name| name.to_owned()).collect();
+        }
+
+        if let Some(path) = matches.value_of("read") {
+            self.read_file = Some(path.to_owned());
+        }
+
+        if matches.is_present("fast") {
+            self.replay_speed = 0.0;
+        } else if let Some(speed) = matches.value_of("speed") {
+            self.replay_speed = speed
+                .parse()
+                .map_err(|e| format!("Bad replay speed {}: {}", speed, e))?;
+        }
+
+        if self.replay_speed < 0.0 {
+            return Err(format!("Bad replay speed {}", self.replay_speed));
+        }
+
+        if let Some(dir) = matches.value_of("record") {
+            self.record_dir = Some(dir.to_owned());
+        }
+
+        if let Some(size) = matches.value_of("record-size") {
+            self.record_size_mb = size
+                .parse()
+                .map_err(|e| format!("Bad recording size {}: {}", size, e))?;
+        }
+
+        if let Some(interval) = matches.value_of("record-interval") {
+            self.record_interval = interval
+                .parse()
+                .map_err(|e| format!("Bad recording interval {}: {}", interval, e))?;
+        }
+
+        if let Some(files) = matches.value_of("record-files") {
+            self.record_files = files
+                .parse()
+                .map_err(|e| format!("Bad recording file count {}: {}", files, e))?;
+        }
+
+        if self.record_files == 0 {
+            return Err("At least one recording file has to be kept".to_owned());
+        }
+
+        if self.record_size_mb == 0 {
+            return Err("Recording size has to be at least 1 MB".to_owned());
+        }
+
+        match matches.value_of("backend") {
+            Some("pcap") => self.backend = Backend::Pcap,
+            Some("pnet") => self.backend = Backend::Pnet,
+            Some(other) => return Err(format!("Unknown backend {}", other)),
+            None => {}
+        }
+
+        if matches.is_present("no-promisc") {
+            self.promisc = false;
+        }
+
+        if let Some(snaplen) = matches.value_of("snaplen") {
+            self.snaplen = snaplen
+                .parse()
+                .map_err(|e| format!("Bad snaplen {}: {}", snaplen, e))?;
+        }
+
+        if let Some(filter) = matches.value_of("filter") {
+            self.filter = Some(filter.to_owned());
+        }
+
+        if let Some(path) = matches.value_of("geoip-city") {
+            self.geoip_city = path.to_owned();
+        }
+
+        if let Some(path) = matches.value_of("geoip-asn") {
+            self.geoip_asn = path.to_owned();
+        }
+
+        if let Some(names) = matches.values_of("enable") {
+            for name in names {
+                self.dissectors.set(name, true)?;
+            }
+        }
+
+        if let Some(names) = matches.values_of("disable") {
+            for name in names {
+                self.dissectors.set(name, false)?;
+            }
+        }
+
+        if let Some(timeout) = matches.value_of("flow-idle-timeout") {
+            self.flow_idle_timeout = timeout
+                .parse()
+                .map_err(|e| format!("Bad flow idle timeout {}: {}", timeout, e))?;
+        }
+
+        if let Some(timeout) = matches.value_of("flow-active-timeout") {
+            self.flow_active_timeout = timeout
+                .parse()
+                .map_err(|e| format!("Bad flow active timeout {}: {}", timeout, e))?;
+        }
+
+        if matches.is_present("debug") {
+            self.debug = true;
+        }
+
+        if matches.is_present("stats") {
+            self.stats = true;
+        }
+
+        Ok(())
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("packet_radar")
+        .about("Realtime network traffic visualization")
+        .arg(
+            Arg::with_name("BIND")
+                .help("Websocket bind address (same as --bind)")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help("Config file (.toml or .json)"),
+        )
+        .arg(
+            Arg::with_name("interface")
+                .short("i")
+                .long("interface")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Capture device, repeat to capture on several devices"),
+        )
+        .arg(
+            Arg::with_name("read")
+                .short("r")
+                .long("read")
+                .takes_value(true)
+                .help("Replay packets from a .pcap or .pcapng file"),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .takes_value(true)
+                .help("Replay speed multiplier, 1 is real-time, 0 is as fast as possible"),
+        )
+        .arg(
+            Arg::with_name("fast")
+                .long("fast")
+                .conflicts_with("speed")
+                .help("Replay as fast as possible (same as --speed 0)"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .short("w")
+                .long("record")
+                .takes_value(true)
+                .help("Record raw frames into rotating pcap files in this directory"),
+        )
+        .arg(
+            Arg::with_name("record-size")
+                .long("record-size")
+                .takes_value(true)
+                .help("Rotate recordings after this many megabytes (default 100)"),
+        )
+        .arg(
+            Arg::with_name("record-interval")
+                .long("record-interval")
+                .takes_value(true)
+                .help("Rotate recordings after this many seconds"),
+        )
+        .arg(
+            Arg::with_name("record-files")
+                .long("record-files")
+                .takes_value(true)
+                .help("Number of recordings to keep (default 10)"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .takes_value(true)
+                .possible_values(&["pcap", "pnet"])
+                .help("Capture backend"),
+        )
+        .arg(
+            Arg::with_name("no-promisc")
+                .long("no-promisc")
+                .help("Do not put the device in promiscuous mode"),
+        )
+        .arg(
+            Arg::with_name("snaplen")
+                .short("s")
+                .long("snaplen")
+                .takes_value(true)
+                .help("Bytes captured per packet (pcap backend)"),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .short("f")
+                .long("filter")
+                .takes_value(true)
+                .help("BPF filter (pcap backend)"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .takes_value(true)
+                .help("Websocket bind address"),
+        )
+        .arg(
+            Arg::with_name("geoip-city")
+                .long("geoip-city")
+                .takes_value(true)
+                .help("Path to GeoLite2-City.mmdb"),
+        )
+        .arg(
+            Arg::with_name("geoip-asn")
+                .long("geoip-asn")
+                .takes_value(true)
+                .help("Path to GeoLite2-ASN.mmdb"),
+        )
+        .arg(
+            Arg::with_name("enable")
+                .long("enable")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Enable a dissector (tcp, udp, icmp, dns, tls, tunnels)"),
+        )
+        .arg(
+            Arg::with_name("disable")
+                .long("disable")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Disable a dissector (tcp, udp, icmp, dns, tls, tunnels)"),
+        )
+        .arg(
+            Arg::with_name("flow-idle-timeout")
+                .long("flow-idle-timeout")
+                .takes_value(true)
+                .help("Seconds without packets before a flow ends (default 60)"),
+        )
+        .arg(
+            Arg::with_name("flow-active-timeout")
+                .long("flow-active-timeout")
+                .takes_value(true)
+                .help("Seconds before long lived flows are reported and restarted (default 1800)"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .long("debug")
+                .help("Print every packet"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print capture metrics every second"),
+        )
+}
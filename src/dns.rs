@@ -1,17 +1,16 @@
+use serde::{Serialize, Serializer};
 use std::fmt;
 use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified, Unaligned};
 
-use dns_lookup::lookup_addr;
-use std::char;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::time::Duration;
 
-lazy_static! {
-    // use a lock until a performance is known, in which this can be swapped for evmap
-    static ref CACHED_IPS_TO_DOMAIN: RwLock<HashMap<String, String>> = Default::default();
-}
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+use super::dns_cache::cached_names;
+use super::reverse_dns::resolve;
 
 /**
  * This module contains a simple DNS parser and DNS utils
@@ -21,20 +20,17 @@ pub fn parse_dns(payload: &[u8]) -> Option<DnsPacket<&[u8]>> {
     DnsPacket::parse(payload)
 }
 
-pub fn reverse_lookup(ip: String) -> String {
-    match CACHED_IPS_TO_DOMAIN.read().unwrap().get(ip.as_str()) {
-        Some(value) => {
-            println!("Using DNS cache domain {} -> {}", ip, value);
-            value.clone()
+/// The name an address was looked up by, or its PTR name
+pub fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    match cached_names(&ip).into_iter().next() {
+        Some(entry) => {
+            println!("Using DNS cache domain {} -> {}", ip, entry.name);
+            Some(entry.name)
         }
-        None => sys_lookup(ip),
+        None => resolve(ip),
     }
 }
 
-fn sys_lookup(ip: String) -> String {
-    lookup_addr(&ip.parse::<IpAddr>().unwrap()).unwrap()
-}
-
 #[derive(FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 struct DnsHeader {
@@ -50,23 +46,66 @@ struct DnsHeader {
     additional_rrs: [u8; 2],
 }
 
+pub const DNS_PORT: u16 = 53;
+
+// compression pointers are offsets from the start of the header
+const HEADER_LEN: usize = 12;
+// RFC 1035 2.3.4, counting the length octets
+const MAX_NAME_LEN: usize = 255;
+
 pub struct DnsPacket<B> {
     header: LayoutVerified<B, DnsHeader>,
     body: B,
 }
 
-enum ParseDns {
-    QuerySection,
-    AnswerSection,
-    REST,
+/// Why a message couldn't be parsed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsError {
+    // ran out of bytes, in the message or in a record's data
+    Truncated,
+    // a compression pointer into the header or past the end
+    BadPointer,
+    // a compression pointer not going back before the name it's in
+    PointerLoop,
+    NameTooLong,
+    // the reserved 01 and 10 label types
+    BadLabel,
+    // record data that doesn't fit its type
+    BadRecord(u16),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsError::Truncated => write!(f, "truncated"),
+            DnsError::BadPointer => write!(f, "compression pointer out of range"),
+            DnsError::PointerLoop => write!(f, "compression pointer loop"),
+            DnsError::NameTooLong => write!(f, "name longer than 255 bytes"),
+            DnsError::BadLabel => write!(f, "reserved label type"),
+            DnsError::BadRecord(rtype) => write!(f, "bad data for record type {}", rtype),
+        }
+    }
 }
 
 // TODO use FromPrimitive
 #[derive(PartialEq)]
 enum RecordTypes {
     A = 1,
+    NS = 2,
     CNAME = 5,
+    SOA = 6,
+    PTR = 12,
+    MX = 15,
+    TXT = 16,
     AAAA = 28, // IPV6
+    SRV = 33,
+    OPT = 41,
+    DS = 43,
+    RRSIG = 46,
+    DNSKEY = 48,
+    SVCB = 64,
+    HTTPS = 65,
+    CAA = 257,
     UNKNOWN,
 }
 
@@ -74,164 +113,273 @@ impl RecordTypes {
     fn from_u16(i: u16) -> RecordTypes {
         match i {
             1 => RecordTypes::A,
+            2 => RecordTypes::NS,
             5 => RecordTypes::CNAME,
+            6 => RecordTypes::SOA,
+            12 => RecordTypes::PTR,
+            15 => RecordTypes::MX,
+            16 => RecordTypes::TXT,
             28 => RecordTypes::AAAA,
+            33 => RecordTypes::SRV,
+            41 => RecordTypes::OPT,
+            43 => RecordTypes::DS,
+            46 => RecordTypes::RRSIG,
+            48 => RecordTypes::DNSKEY,
+            64 => RecordTypes::SVCB,
+            65 => RecordTypes::HTTPS,
+            257 => RecordTypes::CAA,
             _ => RecordTypes::UNKNOWN,
         }
     }
 }
 
-impl<B: ByteSlice> DnsPacket<B> {
-    pub fn parse(bytes: B) -> Option<DnsPacket<B>> {
-        let (header, body) = LayoutVerified::new_unaligned_from_prefix(bytes)?;
-        Some(DnsPacket { header, body })
-    }
+/// Mnemonic for a record type, TYPEn for the ones we don't know (RFC 3597)
+pub fn type_name(rtype: u16) -> String {
+    let name = match RecordTypes::from_u16(rtype) {
+        RecordTypes::A => "A",
+        RecordTypes::NS => "NS",
+        RecordTypes::CNAME => "CNAME",
+        RecordTypes::SOA => "SOA",
+        RecordTypes::PTR => "PTR",
+        RecordTypes::MX => "MX",
+        RecordTypes::TXT => "TXT",
+        RecordTypes::AAAA => "AAAA",
+        RecordTypes::SRV => "SRV",
+        RecordTypes::OPT => "OPT",
+        RecordTypes::DS => "DS",
+        RecordTypes::RRSIG => "RRSIG",
+        RecordTypes::DNSKEY => "DNSKEY",
+        RecordTypes::SVCB => "SVCB",
+        RecordTypes::HTTPS => "HTTPS",
+        RecordTypes::CAA => "CAA",
+        RecordTypes::UNKNOWN => return format!("TYPE{}", rtype),
+    };
+
+    name.to_owned()
+}
 
-    pub fn answers(&self) -> u16 {
-        (self.header.answers[1]).into()
-    }
+pub fn rcode_name(rcode: u16) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        16 => "BADVERS",
+        _ => return format!("RCODE{}", rcode),
+    };
+
+    name.to_owned()
+}
+
+fn serialize_type<S: Serializer>(rtype: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&type_name(*rtype))
+}
 
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsQuestion {
+    pub name: String,
+    #[serde(rename = "type", serialize_with = "serialize_type")]
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SvcParam {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(String),
+    CNAME(String),
+    PTR(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    TXT(Vec<String>),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    CAA {
+        flags: u8,
+        tag: String,
+        value: String,
+    },
+    // HTTPS and SVCB, RFC 9460
+    SVCB {
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+    },
+    DS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: String,
+    },
+    DNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        key_tag: u16,
+    },
+    RRSIG {
+        type_covered: String,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+    },
+    // the EDNS pseudo record, its class and ttl fields are reused
+    OPT {
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    },
+    // types we don't decode, as hex
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsRecord {
+    pub name: String,
+    #[serde(rename = "type", serialize_with = "serialize_type")]
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Every section of a parsed message
+#[derive(Debug, Clone)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
+}
+
+impl DnsMessage {
     pub fn is_reply(&self) -> bool {
-        self.header.transaction_id[0] >> 7 == 0
+        self.flags & 0x8000 != 0
     }
 
-    pub fn questions(&self) -> u16 {
-        (self.header.questions[1]).into()
+    pub fn is_authoritative(&self) -> bool {
+        self.flags & 0x0400 != 0
     }
 
-    fn parse_name(&self, buf: &mut Buf) -> String {
-        // todo: punny code encoding
-        let mut domain = String::new();
-
-        loop {
-            let next = buf.read_u8();
-            if next == 0 {
-                domain.pop();
-                break;
-            };
+    pub fn is_truncated(&self) -> bool {
+        self.flags & 0x0200 != 0
+    }
 
-            let s = buf.read_bytes(next as usize);
-            domain.push_str(&String::from_utf8_lossy(s));
-            domain.push('.');
-        }
+    /// The header's 4 bits, extended by an OPT record's upper 8
+    pub fn rcode(&self) -> u16 {
+        let extended = self
+            .additionals
+            .iter()
+            .filter_map(|record| match record.data {
+                RecordData::OPT { extended_rcode, .. } => Some(extended_rcode as u16),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(0);
+
+        (extended << 4) | (self.flags & 0x000f)
+    }
+}
 
-        domain
+impl<B: ByteSlice> DnsPacket<B> {
+    pub fn parse(bytes: B) -> Option<DnsPacket<B>> {
+        let (header, body) = LayoutVerified::new_unaligned_from_prefix(bytes)?;
+        Some(DnsPacket { header, body })
     }
 
-    pub fn parse_body(&self) {
-        let b = &self.body;
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes(self.header.transaction_id)
+    }
 
-        let mut buf = Buf::new(b);
-        let mut state = ParseDns::QuerySection;
-        let mut domain: String = Default::default();
+    pub fn flags(&self) -> u16 {
+        u16::from_be_bytes(self.header.dns_flags)
+    }
 
-        // Warning, not very safe parsing
-        while buf.avail() {
-            // println!("Buf avail");
-            match state {
-                ParseDns::QuerySection => {
-                    domain = self.parse_name(&mut buf);
+    pub fn is_reply(&self) -> bool {
+        self.header.dns_flags[0] >> 7 == 1
+    }
 
-                    // type
-                    buf.read_16();
+    pub fn questions(&self) -> u16 {
+        u16::from_be_bytes(self.header.questions)
+    }
 
-                    // class
-                    buf.read_16();
+    pub fn answers(&self) -> u16 {
+        u16::from_be_bytes(self.header.answers)
+    }
 
-                    state = ParseDns::AnswerSection;
-                }
+    pub fn authorities(&self) -> u16 {
+        u16::from_be_bytes(self.header.athority_rrs)
+    }
 
-                ParseDns::AnswerSection => {
-                    // println!("DNS Answers");
-                    for _ in 0..self.answers() {
-                        if ((buf.peek_u8() >> 6) & 3) > 0 {
-                            // hardcode domain compression assumption
-                            buf.read_16();
-                        } else {
-                            println!("No domain name compression!");
-                            self.parse_name(&mut buf);
-                        }
-
-                        let ans_type = RecordTypes::from_u16(buf.read_16());
-
-                        // class
-                        buf.read_16();
-
-                        // ttl
-                        buf.read_bytes(4);
-
-                        // read data length
-                        let data_len = buf.read_16() as usize;
-                        let data = buf.read_bytes(data_len);
-
-                        match ans_type {
-                            RecordTypes::A => {
-                                // Address record
-
-                                if data_len != 4 {
-                                    println!("Bad A record!");
-                                    continue;
-                                }
-                                let mut ip_str = String::new();
-
-                                for i in 0..data_len {
-                                    ip_str.push_str(&data[i].to_string());
-
-                                    if i != data_len - 1 {
-                                        ip_str.push('.');
-                                    }
-                                }
-
-                                // println!("Found ipv4 {}", ip_str);
-                                CACHED_IPS_TO_DOMAIN
-                                    .write()
-                                    .unwrap()
-                                    .insert(ip_str.clone(), domain.clone());
-                            }
-
-                            RecordTypes::AAAA => {
-                                if data_len != 16 {
-                                    println!("Bad AAAA record!");
-                                    continue;
-                                }
-                                // IP v6 Address record
-                                let mut ip_str = String::new();
-                                for i in 0..data_len / 2 {
-                                    let hex = u16_val(data[i * 2], data[i * 2 + 1]);
-
-                                    ip_str.push_str(&format!("{:x}", hex));
-
-                                    if i != (data_len / 2 - 1) {
-                                        ip_str.push(':');
-                                    }
-                                }
-
-                                // println!("Found ipv6 AAAA {}", ip_str);
-                                CACHED_IPS_TO_DOMAIN
-                                    .write()
-                                    .unwrap()
-                                    .insert(ip_str.clone(), domain.clone());
-                            }
-
-                            RecordTypes::CNAME => {
-                                // should do a modified name parsing
-                                let cname = String::from_utf8_lossy(data);
-                                // println!("CNAME {}", cname);
-                            }
-
-                            _ => {
-                                // println!("MEOW WRONG TYPE");
-                            }
-                        }
-                    }
-                    break;
-                }
+    pub fn additionals(&self) -> u16 {
+        u16::from_be_bytes(self.header.additional_rrs)
+    }
 
-                _ => {
-                    break;
-                }
-            }
+    /// Reads all four sections, failing rather than reading past the end
+    pub fn parse_body(&self) -> Result<DnsMessage, DnsError> {
+        let mut buf = Buf::new(&self.body);
+
+        let mut questions = Vec::new();
+        for _ in 0..self.questions() {
+            questions.push(DnsQuestion {
+                name: buf.read_name()?,
+                qtype: buf.read_16()?,
+                qclass: buf.read_16()?,
+            });
         }
+
+        Ok(DnsMessage {
+            id: self.id(),
+            flags: self.flags(),
+            questions,
+            answers: read_records(&mut buf, self.answers())?,
+            authorities: read_records(&mut buf, self.authorities())?,
+            additionals: read_records(&mut buf, self.additionals())?,
+        })
     }
 
     pub fn first_name(&self) -> String {
@@ -260,9 +408,296 @@ impl<B: ByteSlice> DnsPacket<B> {
     }
 }
 
-// returns big/network endian from 2 u8s
-fn u16_val(a: u8, b: u8) -> u16 {
-    ((a as u16) << 8) + (b as u16)
+fn read_records(buf: &mut Buf, count: u16) -> Result<Vec<DnsRecord>, DnsError> {
+    let mut records = Vec::new();
+
+    for _ in 0..count {
+        let name = buf.read_name()?;
+        let rtype = buf.read_16()?;
+        let class = buf.read_16()?;
+        let ttl = buf.read_32()?;
+        let data_len = buf.read_16()? as usize;
+
+        let start = buf.pointer;
+        buf.read_bytes(data_len)?;
+        // names in the data can point back anywhere before it
+        let data = record_data(&buf.buf[..buf.pointer], start, rtype, class, ttl)?;
+
+        records.push(DnsRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            data,
+        });
+    }
+
+    Ok(records)
+}
+
+// reads record data from start to the end of message, which stops at
+// the end of the record
+fn record_data(
+    message: &[u8],
+    start: usize,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+) -> Result<RecordData, DnsError> {
+    let mut data = Buf {
+        buf: message,
+        pointer: start,
+    };
+    let len = message.len() - start;
+
+    let record = match RecordTypes::from_u16(rtype) {
+        RecordTypes::A => {
+            if len != 4 {
+                return Err(DnsError::BadRecord(rtype));
+            }
+            let octets = data.read_bytes(4)?;
+            RecordData::A(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        }
+        RecordTypes::AAAA => {
+            if len != 16 {
+                return Err(DnsError::BadRecord(rtype));
+            }
+            let mut octets = [0; 16];
+            octets.copy_from_slice(data.read_bytes(16)?);
+            RecordData::AAAA(Ipv6Addr::from(octets))
+        }
+        RecordTypes::NS => RecordData::NS(data.read_name()?),
+        RecordTypes::CNAME => RecordData::CNAME(data.read_name()?),
+        RecordTypes::PTR => RecordData::PTR(data.read_name()?),
+        RecordTypes::MX => RecordData::MX {
+            preference: data.read_16()?,
+            exchange: data.read_name()?,
+        },
+        RecordTypes::TXT => {
+            let mut strings = Vec::new();
+            while data.avail() {
+                let len = data.read_u8()? as usize;
+                strings.push(String::from_utf8_lossy(data.read_bytes(len)?).into_owned());
+            }
+            RecordData::TXT(strings)
+        }
+        RecordTypes::SRV => RecordData::SRV {
+            priority: data.read_16()?,
+            weight: data.read_16()?,
+            port: data.read_16()?,
+            target: data.read_name()?,
+        },
+        RecordTypes::SOA => RecordData::SOA {
+            mname: data.read_name()?,
+            rname: data.read_name()?,
+            serial: data.read_32()?,
+            refresh: data.read_32()?,
+            retry: data.read_32()?,
+            expire: data.read_32()?,
+            minimum: data.read_32()?,
+        },
+        RecordTypes::CAA => {
+            let flags = data.read_u8()?;
+            let tag_len = data.read_u8()? as usize;
+            let tag = String::from_utf8_lossy(data.read_bytes(tag_len)?).into_owned();
+            let value = String::from_utf8_lossy(data.rest()).into_owned();
+            RecordData::CAA { flags, tag, value }
+        }
+        RecordTypes::SVCB | RecordTypes::HTTPS => {
+            let priority = data.read_16()?;
+            let target = data.read_name()?;
+            let mut params = Vec::new();
+            while data.avail() {
+                let key = data.read_16()?;
+                let len = data.read_16()? as usize;
+                params.push(svc_param(key, data.read_bytes(len)?)?);
+            }
+            RecordData::SVCB {
+                priority,
+                target,
+                params,
+            }
+        }
+        RecordTypes::DS => RecordData::DS {
+            key_tag: data.read_16()?,
+            algorithm: data.read_u8()?,
+            digest_type: data.read_u8()?,
+            digest: hex(data.rest()),
+        },
+        RecordTypes::DNSKEY => RecordData::DNSKEY {
+            flags: data.read_16()?,
+            protocol: data.read_u8()?,
+            algorithm: data.read_u8()?,
+            key_tag: key_tag(&message[start..]),
+        },
+        RecordTypes::RRSIG => RecordData::RRSIG {
+            type_covered: type_name(data.read_16()?),
+            algorithm: data.read_u8()?,
+            labels: data.read_u8()?,
+            original_ttl: data.read_32()?,
+            expiration: data.read_32()?,
+            inception: data.read_32()?,
+            key_tag: data.read_16()?,
+            // the signature follows
+            signer: data.read_name()?,
+        },
+        RecordTypes::OPT => {
+            let mut options = Vec::new();
+            while data.avail() {
+                let code = data.read_16()?;
+                let len = data.read_16()? as usize;
+                options.push(EdnsOption {
+                    code,
+                    data: hex(data.read_bytes(len)?),
+                });
+            }
+            RecordData::OPT {
+                udp_payload_size: class,
+                extended_rcode: (ttl >> 24) as u8,
+                version: (ttl >> 16) as u8,
+                dnssec_ok: ttl & 0x8000 != 0,
+                options,
+            }
+        }
+        RecordTypes::UNKNOWN => RecordData::Unknown(hex(data.rest())),
+    };
+
+    Ok(record)
+}
+
+// SvcParamKeys from RFC 9460 14.3.2, in their presentation format
+fn svc_param(key: u16, value: &[u8]) -> Result<SvcParam, DnsError> {
+    let mut data = Buf::new(value);
+
+    let (key, value) = match key {
+        0 => {
+            let mut keys = Vec::new();
+            while data.avail() {
+                keys.push(svc_key_name(data.read_16()?));
+            }
+            ("mandatory".to_owned(), keys.join(","))
+        }
+        1 => {
+            let mut protocols = Vec::new();
+            while data.avail() {
+                let len = data.read_u8()? as usize;
+                protocols.push(String::from_utf8_lossy(data.read_bytes(len)?).into_owned());
+            }
+            ("alpn".to_owned(), protocols.join(","))
+        }
+        3 => ("port".to_owned(), data.read_16()?.to_string()),
+        4 => {
+            let mut hints = Vec::new();
+            while data.avail() {
+                let octets = data.read_bytes(4)?;
+                hints.push(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).to_string());
+            }
+            ("ipv4hint".to_owned(), hints.join(","))
+        }
+        6 => {
+            let mut hints = Vec::new();
+            while data.avail() {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data.read_bytes(16)?);
+                hints.push(Ipv6Addr::from(octets).to_string());
+            }
+            ("ipv6hint".to_owned(), hints.join(","))
+        }
+        _ => (svc_key_name(key), hex(value)),
+    };
+
+    Ok(SvcParam { key, value })
+}
+
+fn svc_key_name(key: u16) -> String {
+    let name = match key {
+        0 => "mandatory",
+        1 => "alpn",
+        2 => "no-default-alpn",
+        3 => "port",
+        4 => "ipv4hint",
+        5 => "ech",
+        6 => "ipv6hint",
+        _ => return format!("key{}", key),
+    };
+
+    name.to_owned()
+}
+
+// RFC 4034 Appendix B
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        ac += if i & 1 == 1 {
+            *byte as u32
+        } else {
+            (*byte as u32) << 8
+        };
+    }
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Serialize)]
+struct DnsResponseEvent<'a> {
+    r#type: &'static str,
+    client: IpAddr,
+    client_port: u16,
+    server: IpAddr,
+    server_port: u16,
+    ts: Duration,
+    id: u16,
+    rcode: String,
+    authoritative: bool,
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qname: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qtype: Option<String>,
+    // since the matching query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+    answers: &'a [DnsRecord],
+    authorities: &'a [DnsRecord],
+    additionals: &'a [DnsRecord],
+}
+
+/// Publishes a parsed response for the dns views
+pub fn send_response(
+    message: &DnsMessage,
+    server: (IpAddr, u16),
+    client: (IpAddr, u16),
+    latency: Option<Duration>,
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) {
+    let question = message.questions.first();
+
+    let event = DnsResponseEvent {
+        r#type: "dns_response",
+        client: client.0,
+        client_port: client.1,
+        server: server.0,
+        server_port: server.1,
+        ts,
+        id: message.id,
+        rcode: rcode_name(message.rcode()),
+        authoritative: message.is_authoritative(),
+        truncated: message.is_truncated(),
+        qname: question.map(|question| question.name.as_str()),
+        qtype: question.map(|question| type_name(question.qtype)),
+        latency_ms: latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        answers: &message.answers,
+        authorities: &message.authorities,
+        additionals: &message.additionals,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
 }
 
 /* Simple ByteReader */
@@ -273,41 +708,113 @@ struct Buf<'a> {
     pointer: usize,
 }
 
-impl Buf<'_> {
+impl<'a> Buf<'a> {
     pub fn new(buf: &[u8]) -> Buf<'_> {
         Buf { buf, pointer: 0 }
     }
 
-    fn read_u8(&mut self) -> u8 {
-        let val = self.buf[self.pointer];
+    fn read_u8(&mut self) -> Result<u8, DnsError> {
+        let val = *self.buf.get(self.pointer).ok_or(DnsError::Truncated)?;
         self.pointer += 1;
-        val
+        Ok(val)
     }
 
-    fn peek_u8(&mut self) -> u8 {
-        let val = self.buf[self.pointer];
-        val
+    fn read_16(&mut self) -> Result<u16, DnsError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
     }
 
-    fn read_16(&mut self) -> u16 {
-        let val = u16_val(self.buf[self.pointer], self.buf[self.pointer + 1]);
-        self.pointer += 2;
-        val
+    fn read_32(&mut self) -> Result<u32, DnsError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
     }
 
-    fn read_bytes(&mut self, count: usize) -> &[u8] {
-        let start = self.pointer;
-        self.pointer = start + count;
-        &self.buf[start..self.pointer]
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], DnsError> {
+        let end = self.pointer.checked_add(count).ok_or(DnsError::Truncated)?;
+        let bytes = self.buf.get(self.pointer..end).ok_or(DnsError::Truncated)?;
+        self.pointer = end;
+        Ok(bytes)
     }
 
-    fn seek(&mut self, inc: usize) {
-        self.pointer += inc;
+    // the rest of the buffer
+    fn rest(&mut self) -> &'a [u8] {
+        let buf = self.buf;
+        let rest = &buf[self.pointer..];
+        self.pointer = self.buf.len();
+        rest
     }
 
-    fn avail(&mut self) -> bool {
+    fn avail(&self) -> bool {
         self.pointer < self.buf.len()
     }
+
+    /// Reads a possibly compressed name, leaving the pointer after its
+    /// first compression pointer or terminating zero
+    fn read_name(&mut self) -> Result<String, DnsError> {
+        // todo: punny code encoding
+        let mut domain = String::new();
+        let mut name_len = 1;
+        let mut position = self.pointer;
+        // where the labels being read start, pointers have to go before it
+        let mut run_start = position;
+        let mut jumped = false;
+
+        loop {
+            let len = *self.buf.get(position).ok_or(DnsError::Truncated)?;
+
+            match len >> 6 {
+                0 => {
+                    position += 1;
+                    if len == 0 {
+                        break;
+                    }
+
+                    let label = self
+                        .buf
+                        .get(position..position + len as usize)
+                        .ok_or(DnsError::Truncated)?;
+
+                    name_len += 1 + label.len();
+                    if name_len > MAX_NAME_LEN {
+                        return Err(DnsError::NameTooLong);
+                    }
+
+                    if !domain.is_empty() {
+                        domain.push('.');
+                    }
+                    domain.push_str(&String::from_utf8_lossy(label));
+                    position += len as usize;
+                }
+                3 => {
+                    let low = *self.buf.get(position + 1).ok_or(DnsError::Truncated)?;
+                    let offset = ((len as usize & 0x3f) << 8) | low as usize;
+
+                    if offset < HEADER_LEN || offset - HEADER_LEN >= self.buf.len() {
+                        return Err(DnsError::BadPointer);
+                    }
+                    let target = offset - HEADER_LEN;
+                    // only ever going backwards means this ends
+                    if target >= run_start {
+                        return Err(DnsError::PointerLoop);
+                    }
+
+                    if !jumped {
+                        self.pointer = position + 2;
+                        jumped = true;
+                    }
+                    position = target;
+                    run_start = target;
+                }
+                _ => return Err(DnsError::BadLabel),
+            }
+        }
+
+        if !jumped {
+            self.pointer = position;
+        }
+
+        Ok(domain)
+    }
 }
 
 impl fmt::Display for DnsPacket<&[u8]> {
@@ -322,3 +829,163 @@ impl fmt::Display for DnsPacket<&[u8]> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn parse(bytes: &[u8]) -> Option<Result<DnsMessage, DnsError>> {
+        DnsPacket::parse(bytes).map(|packet| packet.parse_body())
+    }
+
+    fn header(questions: u16, answers: u16, authorities: u16, additionals: u16) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x81, 0x80];
+        for count in &[questions, answers, authorities, additionals] {
+            bytes.extend_from_slice(&count.to_be_bytes());
+        }
+        bytes
+    }
+
+    // a question whose name is `name`, followed by type A and class IN
+    fn question(name: &[u8]) -> Vec<u8> {
+        let mut bytes = header(1, 0, 0, 0);
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+        bytes
+    }
+
+    // www.example.com CNAME cdn.example.com A 93.184.216.34, compressed
+    fn response() -> Vec<u8> {
+        let mut bytes = header(1, 2, 0, 0);
+        // question at offset 12
+        bytes.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+        // answer at offset 33, www.example.com CNAME cdn + example.com at offset 16
+        bytes.extend_from_slice(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0x01, 0x2c, 0, 6]);
+        bytes.extend_from_slice(b"\x03cdn\xc0\x10");
+        // cdn.example.com, the CNAME target at offset 45
+        bytes.extend_from_slice(&[0xc0, 0x2d, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        bytes.extend_from_slice(&[93, 184, 216, 34]);
+        bytes
+    }
+
+    #[test]
+    fn compressed_response() {
+        let message = parse(&response()).unwrap().unwrap();
+
+        assert!(message.is_reply());
+        assert_eq!(message.id, 0x1234);
+        assert_eq!(message.questions.len(), 1);
+        assert_eq!(message.questions[0].name, "www.example.com");
+        assert_eq!(message.answers.len(), 2);
+
+        let cname = &message.answers[0];
+        assert_eq!(cname.name, "www.example.com");
+        assert_eq!(cname.ttl, 300);
+        match &cname.data {
+            RecordData::CNAME(target) => assert_eq!(target, "cdn.example.com"),
+            other => panic!("expected a CNAME, got {:?}", other),
+        }
+
+        let a = &message.answers[1];
+        assert_eq!(a.name, "cdn.example.com");
+        assert_eq!(a.ttl, 60);
+        match &a.data {
+            RecordData::A(ip) => assert_eq!(*ip, Ipv4Addr::new(93, 184, 216, 34)),
+            other => panic!("expected an A record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_prefixes() {
+        let response = response();
+        for len in 0..response.len() {
+            match parse(&response[..len]) {
+                // shorter than the header
+                None => assert!(len < HEADER_LEN),
+                Some(result) => assert!(result.is_err(), "prefix of {} bytes parsed", len),
+            }
+        }
+    }
+
+    #[test]
+    fn self_pointer() {
+        let result = parse(&question(&[0xc0, 0x0c])).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::PointerLoop);
+    }
+
+    #[test]
+    fn forward_pointer() {
+        let result = parse(&question(&[0xc0, 0x0e, 0x00])).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::PointerLoop);
+    }
+
+    #[test]
+    fn pointer_into_header() {
+        let result = parse(&question(&[0xc0, 0x05])).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::BadPointer);
+    }
+
+    #[test]
+    fn reserved_label_types() {
+        for label in &[0x40, 0x80] {
+            let result = parse(&question(&[*label, 0x01, 0x00])).unwrap();
+            assert_eq!(result.unwrap_err(), DnsError::BadLabel);
+        }
+    }
+
+    #[test]
+    fn name_too_long() {
+        // 63 + 63 + 63 + 62 byte labels, 256 bytes with the length octets
+        let mut name = Vec::new();
+        for len in &[63, 63, 63, 62] {
+            name.push(*len as u8);
+            name.extend(std::iter::repeat(b'a').take(*len));
+        }
+        name.push(0);
+        assert_eq!(name.len(), 256);
+
+        let result = parse(&question(&name)).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::NameTooLong);
+    }
+
+    #[test]
+    fn counts_without_body() {
+        let result = parse(&header(0xffff, 0xffff, 0xffff, 0xffff)).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::Truncated);
+    }
+
+    #[test]
+    fn rdlength_past_end() {
+        let mut bytes = header(0, 1, 0, 0);
+        // root name, type A, class IN, ttl 60, rdlength 100 with only 4 bytes
+        bytes.extend_from_slice(&[0x00, 0, 1, 0, 1, 0, 0, 0, 60, 0, 100]);
+        bytes.extend_from_slice(&[93, 184, 216, 34]);
+
+        let result = parse(&bytes).unwrap();
+        assert_eq!(result.unwrap_err(), DnsError::Truncated);
+    }
+
+    #[test]
+    fn random_bytes() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let response = response();
+
+        for _ in 0..10000 {
+            let len = rng.gen_range(0, 512);
+            let mut bytes = vec![0u8; len];
+            rng.fill(&mut bytes[..]);
+            let _ = parse(&bytes);
+
+            // a few flipped bytes in an otherwise valid message
+            let mut bytes = response.clone();
+            for _ in 0..rng.gen_range(1, 5) {
+                let i = rng.gen_range(0, bytes.len());
+                bytes[i] = rng.gen();
+            }
+            let _ = parse(&bytes);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use super::dns::{DnsMessage, RecordData};
+
+lazy_static! {
+    // use a lock until a performance is known, in which this can be swapped for evmap
+    static ref HOSTNAMES: RwLock<HostnameCache> = Default::default();
+}
+
+/**
+ * This module remembers which names resolved to which addresses,
+ * following CNAME chains and expiring entries with their TTLs
+ */
+
+const MAX_CACHED_IPS: usize = 10000;
+const MAX_NAMES_PER_IP: usize = 8;
+// longer chains are a loop or a misconfiguration
+const MAX_CNAME_CHAIN: usize = 16;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostnameEntry {
+    // the name that was queried
+    pub name: String,
+    // from the queried name through each CNAME target to the address record
+    pub chain: Vec<String>,
+    // capture time the shortest TTL in the chain runs out
+    pub expires: Duration,
+    pub last_seen: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedIp {
+    pub ip: IpAddr,
+    // most recently seen first
+    pub names: Vec<HostnameEntry>,
+}
+
+#[derive(Default)]
+struct HostnameCache {
+    ips: HashMap<IpAddr, Vec<HostnameEntry>>,
+    // latest capture time seen, entries expire against it
+    now: Duration,
+}
+
+impl HostnameCache {
+    fn insert(&mut self, ip: IpAddr, entry: HostnameEntry) {
+        if !self.ips.contains_key(&ip) && self.ips.len() >= MAX_CACHED_IPS {
+            self.make_room();
+        }
+
+        let names = self.ips.entry(ip).or_insert_with(Vec::new);
+        names.retain(|other| other.name != entry.name);
+        names.insert(0, entry);
+        names.truncate(MAX_NAMES_PER_IP);
+    }
+
+    // drops what has expired, or the address expiring soonest
+    fn make_room(&mut self) {
+        self.purge();
+        if self.ips.len() < MAX_CACHED_IPS {
+            return;
+        }
+
+        let soonest = self
+            .ips
+            .iter()
+            .min_by_key(|(_, names)| names.iter().map(|entry| entry.expires).max())
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = soonest {
+            self.ips.remove(&ip);
+        }
+    }
+
+    fn purge(&mut self) {
+        let now = self.now;
+        for names in self.ips.values_mut() {
+            names.retain(|entry| entry.expires > now);
+        }
+        self.ips.retain(|_, names| !names.is_empty());
+    }
+
+    fn get(&self, ip: &IpAddr) -> Vec<HostnameEntry> {
+        self.ips
+            .get(ip)
+            .map(|names| {
+                names
+                    .iter()
+                    .filter(|entry| entry.expires > self.now)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Remembers the names a response's addresses were looked up by
+pub fn cache_answers(message: &DnsMessage, ts: Duration) {
+    let question = match message.questions.first() {
+        Some(question) => &question.name,
+        None => return,
+    };
+
+    // follow the CNAMEs from the queried name
+    let mut chain = vec![question.clone()];
+    let mut ttl = None;
+    while chain.len() < MAX_CNAME_CHAIN {
+        let current = chain.last().unwrap();
+        let next = message
+            .answers
+            .iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(current))
+            .filter_map(|record| match &record.data {
+                RecordData::CNAME(target) => Some((target.clone(), record.ttl)),
+                _ => None,
+            })
+            .next();
+
+        let (target, record_ttl) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        if chain.iter().any(|name| name.eq_ignore_ascii_case(&target)) {
+            break;
+        }
+
+        ttl = Some(ttl.map_or(record_ttl, |ttl: u32| ttl.min(record_ttl)));
+        chain.push(target);
+    }
+
+    let owner = chain.last().unwrap().clone();
+    let mut cache = HOSTNAMES.write().unwrap();
+    if ts > cache.now {
+        cache.now = ts;
+    }
+
+    for answer in &message.answers {
+        if !answer.name.eq_ignore_ascii_case(&owner) {
+            continue;
+        }
+
+        let ip = match &answer.data {
+            RecordData::A(ip) => IpAddr::V4(*ip),
+            RecordData::AAAA(ip) => IpAddr::V6(*ip),
+            _ => continue,
+        };
+
+        let ttl = ttl.map_or(answer.ttl, |ttl| ttl.min(answer.ttl));
+        cache.insert(
+            ip,
+            HostnameEntry {
+                name: question.clone(),
+                chain: chain.clone(),
+                expires: ts + Duration::from_secs(ttl as u64),
+                last_seen: ts,
+            },
+        );
+    }
+}
+
+/// The names an address was looked up by, most recent first
+pub fn cached_names(ip: &IpAddr) -> Vec<HostnameEntry> {
+    HOSTNAMES.read().unwrap().get(ip)
+}
+
+/// Every address with names that haven't expired
+pub fn dump_cache() -> Vec<CachedIp> {
+    let cache = HOSTNAMES.read().unwrap();
+
+    cache
+        .ips
+        .keys()
+        .map(|ip| CachedIp {
+            ip: *ip,
+            names: cache.get(ip),
+        })
+        .filter(|cached| !cached.names.is_empty())
+        .collect()
+}
@@ -0,0 +1,358 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+use super::dns::{rcode_name, type_name, DnsMessage, DNS_PORT};
+use super::histogram::LatencyHistogram;
+
+lazy_static! {
+    static ref DNS_TRACKER: Mutex<DnsTracker> = Default::default();
+}
+
+/**
+ * This module pairs dns queries with their responses to measure
+ * each resolver's latency, failure rates, retries and the queries
+ * it never answered
+ */
+
+// a query without a response by then is unanswered
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// outstanding queries, new ones aren't tracked past this
+const MAX_PENDING_QUERIES: usize = 65536;
+// spoofed or scanning traffic can come from any address
+const MAX_RESOLVERS: usize = 1000;
+
+// one query on the wire
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QueryKey {
+    client: (IpAddr, u16),
+    resolver: (IpAddr, u16),
+    id: u16,
+    qname: String,
+}
+
+// the same question asked again, from any port or with another id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QuestionKey {
+    client: IpAddr,
+    resolver: IpAddr,
+    qname: String,
+    qtype: u16,
+}
+
+struct PendingQuery {
+    ts: Duration,
+    question: QuestionKey,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolverStats {
+    pub resolver: IpAddr,
+    pub queries: u64,
+    pub responses: u64,
+    pub unanswered: u64,
+    // the same question asked while an earlier query was outstanding
+    pub retries: u64,
+    // responses to queries we didn't see, or already answered
+    pub unmatched: u64,
+    // packets to or from the resolver that didn't parse
+    pub malformed: u64,
+    pub rcodes: HashMap<String, u64>,
+    // fractions of responses, set when the stats are requested
+    pub nxdomain_rate: f64,
+    pub servfail_rate: f64,
+    pub refused_rate: f64,
+    pub latency: LatencyHistogram,
+    pub last_seen: Duration,
+}
+
+impl ResolverStats {
+    fn new(resolver: IpAddr) -> Self {
+        Self {
+            resolver,
+            queries: 0,
+            responses: 0,
+            unanswered: 0,
+            retries: 0,
+            unmatched: 0,
+            malformed: 0,
+            rcodes: HashMap::new(),
+            nxdomain_rate: 0.0,
+            servfail_rate: 0.0,
+            refused_rate: 0.0,
+            latency: Default::default(),
+            last_seen: Duration::default(),
+        }
+    }
+
+    fn rate(&self, rcode: &str) -> f64 {
+        if self.responses == 0 {
+            return 0.0;
+        }
+        *self.rcodes.get(rcode).unwrap_or(&0) as f64 / self.responses as f64
+    }
+}
+
+#[derive(Default)]
+struct DnsTracker {
+    pending: HashMap<QueryKey, PendingQuery>,
+    questions: HashMap<QuestionKey, Vec<QueryKey>>,
+    resolvers: HashMap<IpAddr, ResolverStats>,
+    last_sweep: Duration,
+}
+
+impl DnsTracker {
+    // the stats for a resolver, evicting the least recently seen to make room
+    fn resolver(&mut self, resolver: IpAddr, ts: Duration) -> &mut ResolverStats {
+        if !self.resolvers.contains_key(&resolver) && self.resolvers.len() >= MAX_RESOLVERS {
+            let oldest = self
+                .resolvers
+                .values()
+                .min_by_key(|stats| stats.last_seen)
+                .map(|stats| stats.resolver);
+            if let Some(oldest) = oldest {
+                self.resolvers.remove(&oldest);
+            }
+        }
+
+        let stats = self
+            .resolvers
+            .entry(resolver)
+            .or_insert_with(|| ResolverStats::new(resolver));
+        if ts > stats.last_seen {
+            stats.last_seen = ts;
+        }
+        stats
+    }
+
+    fn remove(&mut self, key: &QueryKey) -> Option<PendingQuery> {
+        let query = self.pending.remove(key)?;
+
+        let empty = match self.questions.get_mut(&query.question) {
+            Some(keys) => {
+                keys.retain(|other| other != key);
+                keys.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.questions.remove(&query.question);
+        }
+
+        Some(query)
+    }
+
+    // expires unanswered queries, in capture time
+    fn sweep(&mut self, ts: Duration, tx: &Sender<OwnedMessage>) {
+        if ts.checked_sub(self.last_sweep).unwrap_or_default() < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = ts;
+
+        let expired: Vec<QueryKey> = self
+            .pending
+            .iter()
+            .filter(|(_, query)| ts.checked_sub(query.ts).unwrap_or_default() > QUERY_TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        self.time_out(expired, tx);
+    }
+
+    // counts the queries as unanswered
+    fn time_out(&mut self, keys: Vec<QueryKey>, tx: &Sender<OwnedMessage>) {
+        for key in keys {
+            if let Some(query) = self.remove(&key) {
+                self.resolver(key.resolver.0, query.ts).unanswered += 1;
+                send_timeout(tx, &key, &query);
+            }
+        }
+    }
+}
+
+fn query_key(
+    message: &DnsMessage,
+    client: (IpAddr, u16),
+    resolver: (IpAddr, u16),
+) -> Option<(QueryKey, QuestionKey)> {
+    let question = message.questions.first()?;
+    // resolvers may echo the name with its case changed, dns 0x20
+    let qname = question.name.to_lowercase();
+
+    let key = QueryKey {
+        client,
+        resolver,
+        id: message.id,
+        qname: qname.clone(),
+    };
+    let question = QuestionKey {
+        client: client.0,
+        resolver: resolver.0,
+        qname,
+        qtype: question.qtype,
+    };
+
+    Some((key, question))
+}
+
+/// Starts timing a query sent to a resolver
+pub fn track_query(
+    message: &DnsMessage,
+    client: (IpAddr, u16),
+    resolver: (IpAddr, u16),
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) {
+    let mut tracker = DNS_TRACKER.lock().unwrap();
+    tracker.sweep(ts, tx);
+
+    let (key, question) = match query_key(message, client, resolver) {
+        Some(keys) => keys,
+        None => return,
+    };
+
+    tracker.resolver(resolver.0, ts).queries += 1;
+
+    // the same packet again, the first one is still the one timed
+    if tracker.pending.contains_key(&key) {
+        tracker.resolver(resolver.0, ts).retries += 1;
+        return;
+    }
+
+    if tracker.questions.contains_key(&question) {
+        tracker.resolver(resolver.0, ts).retries += 1;
+    }
+
+    if tracker.pending.len() >= MAX_PENDING_QUERIES {
+        return;
+    }
+
+    tracker
+        .questions
+        .entry(question.clone())
+        .or_insert_with(Vec::new)
+        .push(key.clone());
+    tracker.pending.insert(key, PendingQuery { ts, question });
+}
+
+/// Matches a response to its query, returning how long the resolver took
+pub fn match_response(
+    message: &DnsMessage,
+    resolver: (IpAddr, u16),
+    client: (IpAddr, u16),
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) -> Option<Duration> {
+    let mut tracker = DNS_TRACKER.lock().unwrap();
+    tracker.sweep(ts, tx);
+
+    {
+        let stats = tracker.resolver(resolver.0, ts);
+        stats.responses += 1;
+        *stats.rcodes.entry(rcode_name(message.rcode())).or_insert(0) += 1;
+    }
+
+    let query = query_key(message, client, resolver).and_then(|(key, _)| tracker.remove(&key));
+    let query = match query {
+        Some(query) => query,
+        None => {
+            tracker.resolver(resolver.0, ts).unmatched += 1;
+            return None;
+        }
+    };
+
+    // the client has its answer, retries of the question aren't unanswered
+    if let Some(retries) = tracker.questions.get(&query.question).cloned() {
+        for key in retries {
+            tracker.remove(&key);
+        }
+    }
+
+    let latency = ts.checked_sub(query.ts).unwrap_or_default();
+    tracker.resolver(resolver.0, ts).latency.add(latency);
+
+    Some(latency)
+}
+
+/// Counts a packet that didn't parse against the resolver it was to or from
+pub fn track_malformed(source: (IpAddr, u16), destination: (IpAddr, u16), ts: Duration) {
+    let resolver = if source.1 == DNS_PORT {
+        source.0
+    } else {
+        destination.0
+    };
+
+    DNS_TRACKER.lock().unwrap().resolver(resolver, ts).malformed += 1;
+}
+
+/// Reports unanswered queries while no dns packets arrive to do it
+pub fn expire_queries(now: Duration, tx: &Sender<OwnedMessage>) {
+    DNS_TRACKER.lock().unwrap().sweep(now, tx);
+}
+
+/// Reports every outstanding query as unanswered, once there are no more packets to see
+pub fn end_all_queries(tx: &Sender<OwnedMessage>) {
+    let mut tracker = DNS_TRACKER.lock().unwrap();
+
+    let pending: Vec<QueryKey> = tracker.pending.keys().cloned().collect();
+    tracker.time_out(pending, tx);
+}
+
+#[derive(Serialize)]
+struct DnsTimeoutEvent<'a> {
+    r#type: &'static str,
+    client: IpAddr,
+    client_port: u16,
+    resolver: IpAddr,
+    resolver_port: u16,
+    id: u16,
+    qname: &'a str,
+    qtype: String,
+    // when the query was sent
+    ts: Duration,
+}
+
+fn send_timeout(tx: &Sender<OwnedMessage>, key: &QueryKey, query: &PendingQuery) {
+    let event = DnsTimeoutEvent {
+        r#type: "dns_timeout",
+        client: key.client.0,
+        client_port: key.client.1,
+        resolver: key.resolver.0,
+        resolver_port: key.resolver.1,
+        id: key.id,
+        qname: &key.qname,
+        qtype: type_name(query.question.qtype),
+        ts: query.ts,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+/// Resolvers with the most queries first
+pub fn resolver_stats() -> Vec<ResolverStats> {
+    let mut resolvers: Vec<ResolverStats> = DNS_TRACKER
+        .lock()
+        .unwrap()
+        .resolvers
+        .values()
+        .cloned()
+        .collect();
+
+    for stats in resolvers.iter_mut() {
+        stats.nxdomain_rate = stats.rate("NXDOMAIN");
+        stats.servfail_rate = stats.rate("SERVFAIL");
+        stats.refused_rate = stats.rate("REFUSED");
+        stats.latency.update_percentiles();
+    }
+
+    resolvers.sort_by(|a, b| b.queries.cmp(&a.queries));
+    resolvers
+}
@@ -0,0 +1,382 @@
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use tls_parser::{TlsClientHelloContents, TlsExtension, TlsExtensionType, TlsServerHelloContents};
+
+use super::tls::{highest_version, is_grease};
+
+/**
+ * This module builds tls client and server fingerprints,
+ * JA3/JA3S (https://github.com/salesforce/ja3) and
+ * JA4/JA4S (https://github.com/FoxIO-LLC/ja4)
+ */
+
+const EXTENSION_SNI: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+
+/// SSLVersion,Cipher,SSLExtension,EllipticCurve,EllipticCurvePointFormat
+pub fn build_ja3_fingerprint(
+    content: &TlsClientHelloContents,
+    extensions: &Vec<TlsExtension>,
+) -> String {
+    let mut ja3 = format!("{},", u16::from(content.version));
+
+    let ciphers = content
+        .ciphers
+        .iter()
+        .map(|x| x.0)
+        .filter(|x| !is_grease(*x))
+        .join("-");
+    ja3.push_str(&ciphers);
+    ja3.push(',');
+
+    let ext_str = extensions
+        .iter()
+        .map(|x| TlsExtensionType::from(x))
+        .map(|x| u16::from(x))
+        .filter(|x| !is_grease(*x))
+        .join("-");
+    ja3.push_str(&ext_str);
+    ja3.push(',');
+
+    for ext in extensions {
+        match ext {
+            &TlsExtension::EllipticCurves(ref ec) => {
+                ja3.push_str(&ec.iter().map(|x| x.0).filter(|x| !is_grease(*x)).join("-"));
+            }
+            _ => (),
+        }
+    }
+    ja3.push(',');
+
+    for ext in extensions {
+        match ext {
+            &TlsExtension::EcPointFormats(ref pf) => {
+                ja3.push_str(&pf.iter().join("-"));
+            }
+            _ => (),
+        }
+    }
+
+    ja3
+}
+
+/// SSLVersion,Cipher,SSLExtension
+pub fn build_ja3s_fingerprint(content: &TlsServerHelloContents, raw_extensions: &[u8]) -> String {
+    format!(
+        "{},{},{}",
+        u16::from(content.version),
+        content.cipher.0,
+        extension_types(raw_extensions).iter().join("-")
+    )
+}
+
+pub fn ja3_hash(ja3: &str) -> String {
+    format!("{:x}", md5::compute(ja3))
+}
+
+/// eg. t13d1516h2_8daaf6152771_e5627efa2ab1, ciphers and extensions
+/// are sorted so extension order randomization doesn't change it
+pub fn build_ja4_fingerprint(
+    content: &TlsClientHelloContents,
+    extensions: &[TlsExtension],
+    raw_extensions: &[u8],
+) -> String {
+    let mut version = content.version.0;
+    let mut sni = false;
+    let mut alpn = None;
+    let mut signature_algorithms = Vec::new();
+
+    for ext in extensions {
+        match ext {
+            TlsExtension::SupportedVersions(versions) => {
+                version = highest_version(version, versions);
+            }
+            TlsExtension::SNI(_) => sni = true,
+            TlsExtension::ALPN(protocols) => alpn = protocols.first().cloned(),
+            TlsExtension::SignatureAlgorithms(algorithms) => {
+                signature_algorithms = algorithms.iter().filter(|id| !is_grease(**id)).collect()
+            }
+            _ => {}
+        }
+    }
+
+    let mut ciphers: Vec<u16> = content
+        .ciphers
+        .iter()
+        .map(|cipher| cipher.0)
+        .filter(|id| !is_grease(*id))
+        .collect();
+    let mut types = extension_types(raw_extensions);
+
+    let prefix = format!(
+        "t{}{}{:02}{:02}{}",
+        version_code(version),
+        if sni { "d" } else { "i" },
+        ciphers.len().min(99),
+        types.len().min(99),
+        alpn_code(alpn)
+    );
+
+    ciphers.sort();
+    let ciphers = if ciphers.is_empty() {
+        empty_hash()
+    } else {
+        hash_prefix(&ciphers.iter().map(|id| format!("{:04x}", id)).join(","))
+    };
+
+    // sni and alpn are already in the prefix
+    types.retain(|id| *id != EXTENSION_SNI && *id != EXTENSION_ALPN);
+    types.sort();
+    let mut extensions = types.iter().map(|id| format!("{:04x}", id)).join(",");
+    if !signature_algorithms.is_empty() {
+        extensions.push('_');
+        extensions.push_str(
+            &signature_algorithms
+                .iter()
+                .map(|id| format!("{:04x}", id))
+                .join(","),
+        );
+    }
+    let extensions = if types.is_empty() {
+        empty_hash()
+    } else {
+        hash_prefix(&extensions)
+    };
+
+    format!("{}_{}_{}", prefix, ciphers, extensions)
+}
+
+/// eg. t130200_1301_234ea6891581, extensions stay in the server's order
+pub fn build_ja4s_fingerprint(
+    content: &TlsServerHelloContents,
+    extensions: &[TlsExtension],
+    raw_extensions: &[u8],
+) -> String {
+    let mut version = content.version.0;
+    let mut alpn = None;
+
+    for ext in extensions {
+        match ext {
+            TlsExtension::SupportedVersions(versions) => {
+                version = highest_version(version, versions);
+            }
+            TlsExtension::ALPN(protocols) => alpn = protocols.first().cloned(),
+            _ => {}
+        }
+    }
+
+    let types = extension_types(raw_extensions);
+    let extensions = if types.is_empty() {
+        empty_hash()
+    } else {
+        hash_prefix(&types.iter().map(|id| format!("{:04x}", id)).join(","))
+    };
+
+    format!(
+        "t{}{:02}{}_{:04x}_{}",
+        version_code(version),
+        types.len().min(99),
+        alpn_code(alpn),
+        content.cipher.0,
+        extensions
+    )
+}
+
+// extension types in the order sent, read directly so an extension
+// tls-parser rejects doesn't lose the rest
+fn extension_types(raw_extensions: &[u8]) -> Vec<u16> {
+    let mut types = Vec::new();
+    let mut offset = 0;
+
+    while offset + 4 <= raw_extensions.len() {
+        let id = u16::from_be_bytes([raw_extensions[offset], raw_extensions[offset + 1]]);
+        let len =
+            u16::from_be_bytes([raw_extensions[offset + 2], raw_extensions[offset + 3]]) as usize;
+
+        if !is_grease(id) {
+            types.push(id);
+        }
+        offset += 4 + len;
+    }
+
+    types
+}
+
+fn version_code(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    }
+}
+
+// first and last characters of the protocol, or of its hex if not alphanumeric
+fn alpn_code(alpn: Option<&[u8]>) -> String {
+    let alpn = match alpn {
+        Some(alpn) if !alpn.is_empty() => alpn,
+        _ => return "00".to_owned(),
+    };
+
+    let first = alpn[0];
+    let last = alpn[alpn.len() - 1];
+
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first as char, last as char)
+    } else {
+        let hex = format!("{:02x}{:02x}", first, last);
+        format!("{}{}", &hex[..1], &hex[3..])
+    }
+}
+
+// first 12 hex characters of the sha256
+fn hash_prefix(value: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(value.as_bytes()));
+    hash[..12].to_owned()
+}
+
+fn empty_hash() -> String {
+    "000000000000".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tls_parser::{SNIType, TlsCipherSuiteID, TlsCompressionID, TlsVersion};
+
+    // the extensions as sent, with empty bodies, only their types are read
+    fn raw_extensions(types: &[u16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for id in types {
+            bytes.extend_from_slice(&id.to_be_bytes());
+            bytes.extend_from_slice(&[0, 0]);
+        }
+        bytes
+    }
+
+    fn client_hello(ciphers: &[u16]) -> TlsClientHelloContents<'static> {
+        TlsClientHelloContents {
+            version: TlsVersion(0x0303),
+            rand_time: 0,
+            rand_data: &[0; 28],
+            session_id: None,
+            ciphers: ciphers.iter().map(|id| TlsCipherSuiteID(*id)).collect(),
+            comp: vec![TlsCompressionID(0)],
+            ext: None,
+        }
+    }
+
+    fn server_hello(cipher: u16) -> TlsServerHelloContents<'static> {
+        TlsServerHelloContents {
+            version: TlsVersion(0x0303),
+            rand_time: 0,
+            rand_data: &[0; 28],
+            session_id: None,
+            cipher: TlsCipherSuiteID(cipher),
+            compression: TlsCompressionID(0),
+            ext: None,
+        }
+    }
+
+    // the client hello from the JA4 technical details, with grease added
+    const SPEC_CIPHERS: &[u16] = &[
+        0x2a2a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+        0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ];
+    const SPEC_EXTENSIONS: &[u16] = &[
+        0x0a0a, 0x001b, 0x0000, 0x0033, 0x0010, 0x4469, 0x0017, 0x002d, 0x000d, 0x0005, 0x0023,
+        0x0012, 0x002b, 0xff01, 0x000b, 0x000a, 0x0015, 0x1a1a,
+    ];
+    const SPEC_SIGNATURE_ALGORITHMS: &[u16] = &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ];
+
+    fn spec_extensions() -> Vec<TlsExtension<'static>> {
+        vec![
+            TlsExtension::SNI(vec![(SNIType(0), &b"example.com"[..])]),
+            TlsExtension::ALPN(vec![b"h2", b"http/1.1"]),
+            TlsExtension::SignatureAlgorithms(SPEC_SIGNATURE_ALGORITHMS.to_vec()),
+            TlsExtension::SupportedVersions(vec![
+                TlsVersion(0x3a3a),
+                TlsVersion(0x0304),
+                TlsVersion(0x0303),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn ja4_spec_example() {
+        let ja4 = build_ja4_fingerprint(
+            &client_hello(SPEC_CIPHERS),
+            &spec_extensions(),
+            &raw_extensions(SPEC_EXTENSIONS),
+        );
+        assert_eq!(ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_ignores_order() {
+        let mut ciphers = SPEC_CIPHERS.to_vec();
+        ciphers.reverse();
+        let mut types = SPEC_EXTENSIONS.to_vec();
+        types.rotate_left(5);
+
+        let ja4 = build_ja4_fingerprint(
+            &client_hello(&ciphers),
+            &spec_extensions(),
+            &raw_extensions(&types),
+        );
+        assert_eq!(ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_without_sni_or_extensions() {
+        let ja4 = build_ja4_fingerprint(&client_hello(&[]), &[], &[]);
+        assert_eq!(ja4, "t12i000000_000000000000_000000000000");
+    }
+
+    #[test]
+    fn ja4s_spec_example() {
+        let extensions = vec![TlsExtension::SupportedVersions(vec![TlsVersion(0x0304)])];
+
+        let ja4s = build_ja4s_fingerprint(
+            &server_hello(0x1301),
+            &extensions,
+            &raw_extensions(&[0x0033, 0x002b]),
+        );
+        assert_eq!(ja4s, "t130200_1301_234ea6891581");
+    }
+
+    #[test]
+    fn ja4s_keeps_server_order() {
+        let ja4s = build_ja4s_fingerprint(
+            &server_hello(0xc030),
+            &[TlsExtension::ALPN(vec![b"http/1.1"])],
+            &raw_extensions(&[0x002b, 0x0033, 0x0010]),
+        );
+        assert!(ja4s.starts_with("t1203h1_c030_"));
+        assert_ne!(&ja4s[13..], "234ea6891581");
+    }
+
+    #[test]
+    fn alpn_codes() {
+        assert_eq!(alpn_code(None), "00");
+        assert_eq!(alpn_code(Some(b"")), "00");
+        assert_eq!(alpn_code(Some(b"h2")), "h2");
+        assert_eq!(alpn_code(Some(b"http/1.1")), "h1");
+        assert_eq!(alpn_code(Some(&[0xab, 0xcd])), "ad");
+        assert_eq!(alpn_code(Some(&[0xab])), "ab");
+    }
+
+    #[test]
+    fn truncated_extensions() {
+        // the last extension's header is cut short
+        let mut raw = raw_extensions(&[0x0000, 0x0010]);
+        raw.extend_from_slice(&[0x00, 0x2b, 0x00]);
+        assert_eq!(extension_types(&raw), vec![0x0000, 0x0010]);
+    }
+}
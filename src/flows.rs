@@ -0,0 +1,340 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+use super::config::CONFIG;
+
+lazy_static! {
+    static ref FLOWS: Mutex<FlowTable> = Default::default();
+}
+
+/**
+ * This module keeps a bidirectional flow table of tcp and udp
+ * conversations, emitting flow_start and flow_end events
+ */
+
+// how often (in capture time) the table is checked for expired flows
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Direction independent 5-tuple, the lower endpoint always comes first
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub lower: (IpAddr, u16),
+    pub upper: (IpAddr, u16),
+    // innermost vlan tag
+    pub vlan: Option<u16>,
+    pub interface: String,
+}
+
+impl FlowKey {
+    pub fn new(
+        protocol: u8,
+        source: (IpAddr, u16),
+        destination: (IpAddr, u16),
+        vlan: Option<u16>,
+        interface: &str,
+    ) -> Self {
+        let (lower, upper) = if source < destination {
+            (source, destination)
+        } else {
+            (destination, source)
+        };
+
+        Self {
+            protocol,
+            lower,
+            upper,
+            vlan,
+            interface: interface.to_owned(),
+        }
+    }
+
+    /// The other end of the conversation from `endpoint`
+    pub fn peer(&self, endpoint: (IpAddr, u16)) -> (IpAddr, u16) {
+        if endpoint == self.lower {
+            self.upper
+        } else {
+            self.lower
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Flow {
+    pub id: u64,
+    pub protocol: &'static str,
+    pub interface: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan: Option<u16>,
+    // the side that sent the first packet we saw
+    pub client: IpAddr,
+    pub client_port: u16,
+    pub server: IpAddr,
+    pub server_port: u16,
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    pub client_packets: u64,
+    pub client_bytes: u64,
+    pub server_packets: u64,
+    pub server_bytes: u64,
+}
+
+impl Flow {
+    pub fn is_from_client(&self, source: IpAddr, source_port: u16) -> bool {
+        self.client == source && self.client_port == source_port
+    }
+}
+
+#[derive(Serialize)]
+struct FlowEvent<'a> {
+    r#type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(flatten)]
+    flow: &'a Flow,
+}
+
+/// What a sweep found
+#[derive(Default)]
+pub struct ExpiredFlows {
+    // flows past the idle timeout, removed from the table
+    pub idle: Vec<(FlowKey, Flow)>,
+    // flows past the active timeout, as they ended and as they carry on
+    pub restarted: Vec<(Flow, Flow)>,
+}
+
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    next_id: u64,
+    last_sweep: Duration,
+}
+
+impl Default for FlowTable {
+    fn default() -> FlowTable {
+        FlowTable::new()
+    }
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self {
+            flows: Default::default(),
+            next_id: 1,
+            last_sweep: Duration::new(0, 0),
+        }
+    }
+
+    /// Counts a packet against its flow, creating the flow if needed.
+    /// Returns the flow and whether this packet started it
+    pub fn update(
+        &mut self,
+        key: &FlowKey,
+        source: (IpAddr, u16),
+        destination: (IpAddr, u16),
+        len: usize,
+        ts: Duration,
+    ) -> (&Flow, bool) {
+        let mut created = false;
+        let next_id = &mut self.next_id;
+
+        let flow = self.flows.entry(key.clone()).or_insert_with(|| {
+            created = true;
+            let id = *next_id;
+            *next_id += 1;
+
+            Flow {
+                id,
+                protocol: protocol_name(key.protocol),
+                interface: key.interface.clone(),
+                vlan: key.vlan,
+                client: source.0,
+                client_port: source.1,
+                server: destination.0,
+                server_port: destination.1,
+                first_seen: ts,
+                last_seen: ts,
+                client_packets: 0,
+                client_bytes: 0,
+                server_packets: 0,
+                server_bytes: 0,
+            }
+        });
+
+        if flow.is_from_client(source.0, source.1) {
+            flow.client_packets += 1;
+            flow.client_bytes += len as u64;
+        } else {
+            flow.server_packets += 1;
+            flow.server_bytes += len as u64;
+        }
+
+        // timestamps from different interfaces may interleave
+        if ts > flow.last_seen {
+            flow.last_seen = ts;
+        }
+
+        (flow, created)
+    }
+
+    pub fn remove(&mut self, key: &FlowKey) -> Option<Flow> {
+        self.flows.remove(key)
+    }
+
+    /// Removes flows past the idle timeout and restarts the counters
+    /// of flows past the active timeout
+    pub fn expire(&mut self, now: Duration) -> ExpiredFlows {
+        let mut expired = ExpiredFlows::default();
+        if now < self.last_sweep + SWEEP_INTERVAL {
+            return expired;
+        }
+        self.last_sweep = now;
+
+        let idle_timeout = Duration::from_secs(CONFIG.flow_idle_timeout);
+        let active_timeout = Duration::from_secs(CONFIG.flow_active_timeout);
+
+        let idle: Vec<FlowKey> = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| now > flow.last_seen + idle_timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in idle {
+            if let Some(flow) = self.flows.remove(&key) {
+                expired.idle.push((key, flow));
+            }
+        }
+
+        // the connection carries on, only its record is cut
+        for flow in self.flows.values_mut() {
+            if now > flow.first_seen + active_timeout {
+                let ended = flow.clone();
+
+                flow.id = self.next_id;
+                self.next_id += 1;
+                flow.first_seen = now;
+                flow.client_packets = 0;
+                flow.client_bytes = 0;
+                flow.server_packets = 0;
+                flow.server_bytes = 0;
+
+                expired.restarted.push((ended, flow.clone()));
+            }
+        }
+
+        expired
+    }
+
+    /// Removes every flow, eg. when a replayed capture runs out
+    pub fn drain(&mut self) -> Vec<(FlowKey, Flow)> {
+        self.flows.drain().collect()
+    }
+
+    pub fn flows(&self) -> Vec<Flow> {
+        self.flows.values().cloned().collect()
+    }
+}
+
+fn protocol_name(protocol: u8) -> &'static str {
+    match protocol {
+        6 => "tcp",
+        17 => "udp",
+        _ => "other",
+    }
+}
+
+fn send_event(
+    tx: &Sender<OwnedMessage>,
+    event_type: &'static str,
+    reason: Option<&'static str>,
+    flow: &Flow,
+) {
+    let event = FlowEvent {
+        r#type: event_type,
+        reason,
+        flow,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+/// Tracks a packet, emitting events for new and expired flows
+pub fn track_flow(
+    key: &FlowKey,
+    source: (IpAddr, u16),
+    destination: (IpAddr, u16),
+    len: usize,
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) {
+    let expired = {
+        let mut flows = FLOWS.lock().unwrap();
+
+        let (flow, created) = flows.update(key, source, destination, len, ts);
+        if created {
+            send_event(tx, "flow_start", None, flow);
+        }
+
+        flows.expire(ts)
+    };
+
+    end_expired(expired, tx);
+}
+
+/// Sweeps for expired flows while no packets arrive to do it
+pub fn expire_flows(now: Duration, tx: &Sender<OwnedMessage>) {
+    let expired = FLOWS.lock().unwrap().expire(now);
+    end_expired(expired, tx);
+}
+
+/// Ends every flow still open, once there are no more packets to see
+pub fn end_all_flows(reason: &'static str, tx: &Sender<OwnedMessage>) {
+    let flows = FLOWS.lock().unwrap().drain();
+
+    for (key, flow) in flows {
+        send_event(tx, "flow_end", Some(reason), &flow);
+        forget_flow(&key);
+    }
+}
+
+fn end_expired(expired: ExpiredFlows, tx: &Sender<OwnedMessage>) {
+    for (key, flow) in expired.idle {
+        send_event(tx, "flow_end", Some("idle"), &flow);
+        forget_flow(&key);
+    }
+
+    // the dissectors keep their state for the continuing flow
+    for (ended, flow) in expired.restarted {
+        send_event(tx, "flow_end", Some("active"), &ended);
+        send_event(tx, "flow_start", None, &flow);
+    }
+}
+
+/// Ends a flow before its timeout, eg. when a tcp connection closes
+pub fn end_flow(key: &FlowKey, reason: &'static str, tx: &Sender<OwnedMessage>) {
+    let flow = FLOWS.lock().unwrap().remove(key);
+
+    if let Some(flow) = flow {
+        send_event(tx, "flow_end", Some(reason), &flow);
+        forget_flow(key);
+    }
+}
+
+pub fn current_flows() -> Vec<Flow> {
+    FLOWS.lock().unwrap().flows()
+}
+
+// drops per flow state kept by the dissectors
+fn forget_flow(key: &FlowKey) {
+    super::tcp::forget_conn(key);
+    super::tcp_state::forget_connection(key);
+    super::tcp_stream::forget_stream(key);
+}
@@ -0,0 +1,430 @@
+use serde::Serialize;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    static ref REASSEMBLER: Mutex<Reassembler> = Default::default();
+}
+
+/**
+ * This module reassembles fragmented IPv4 datagrams and IPv6 packets
+ * carrying a Fragment extension header
+ */
+
+// incomplete datagrams are dropped after this long
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+// memory held by incomplete datagrams, the oldest are evicted beyond this
+const MAX_BUFFERED_BYTES: usize = 4 * 1024 * 1024;
+const MAX_DATAGRAMS: usize = 1024;
+// ipv4 total length and ipv6 payload length are 16 bits
+const MAX_DATAGRAM_LEN: usize = 65535;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub id: u32,
+    pub protocol: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FragmentStats {
+    pub fragments: u64,
+    pub reassembled: u64,
+    // fragments overlapping data already received, the earlier data is kept
+    pub overlaps: u64,
+    pub duplicates: u64,
+    pub timeouts: u64,
+    pub evicted: u64,
+    // fragments that would grow a datagram past 65535 bytes
+    pub oversized: u64,
+    // a second last fragment disagreeing on the length, or data past the end
+    pub bad_length: u64,
+    pub pending: usize,
+    pub buffered_bytes: usize,
+}
+
+struct PartialDatagram {
+    // capture time of the first fragment
+    started: Duration,
+    data: Vec<u8>,
+    // received byte ranges, sorted and merged
+    ranges: Vec<(usize, usize)>,
+    // known once the last fragment arrives
+    total_len: Option<usize>,
+}
+
+impl PartialDatagram {
+    fn new(ts: Duration) -> Self {
+        Self {
+            started: ts,
+            data: Vec::new(),
+            ranges: Vec::new(),
+            total_len: None,
+        }
+    }
+
+    /// Copies the fragment into the gaps of what we have so far,
+    /// returns how many bytes were already present
+    fn insert(&mut self, offset: usize, payload: &[u8]) -> usize {
+        let end = offset + payload.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+
+        let mut overlapped = 0;
+        let mut cursor = offset;
+        for &(start, stop) in &self.ranges {
+            if stop <= cursor {
+                continue;
+            }
+            if start >= end {
+                break;
+            }
+            if start > cursor {
+                self.data[cursor..start].copy_from_slice(&payload[cursor - offset..start - offset]);
+            }
+
+            let covered_end = cmp::min(stop, end);
+            overlapped += covered_end - cmp::max(start, cursor);
+            cursor = cmp::max(cursor, stop);
+        }
+
+        if cursor < end {
+            self.data[cursor..end].copy_from_slice(&payload[cursor - offset..]);
+        }
+
+        self.add_range(offset, end);
+
+        overlapped
+    }
+
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.ranges.push((start, end));
+        self.ranges.sort();
+
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = cmp::max(last.1, end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        self.ranges = merged;
+    }
+
+    fn bad_length(&self, end: usize, more_fragments: bool) -> bool {
+        match self.total_len {
+            Some(total_len) => end > total_len || (!more_fragments && end != total_len),
+            None => !more_fragments && self.data.len() > end,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.total_len {
+            Some(total_len) => self.ranges == [(0, total_len)],
+            None => false,
+        }
+    }
+}
+
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, PartialDatagram>,
+    buffered_bytes: usize,
+    last_sweep: Duration,
+    stats: FragmentStats,
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new()
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            pending: Default::default(),
+            buffered_bytes: 0,
+            last_sweep: Duration::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Adds a fragment, returns the full payload once every fragment has arrived.
+    /// `offset` is in bytes, `more_fragments` is false for the last fragment
+    pub fn add(
+        &mut self,
+        key: FragmentKey,
+        offset: usize,
+        more_fragments: bool,
+        payload: &[u8],
+        ts: Duration,
+    ) -> Option<Vec<u8>> {
+        self.stats.fragments += 1;
+        self.expire(ts);
+
+        let end = offset + payload.len();
+        if end > MAX_DATAGRAM_LEN {
+            self.stats.oversized += 1;
+            self.drop_datagram(&key);
+            return None;
+        }
+
+        let (bad_length, before) = match self.pending.get(&key) {
+            Some(datagram) => (
+                datagram.bad_length(end, more_fragments),
+                datagram.data.len(),
+            ),
+            None => (false, 0),
+        };
+        if bad_length {
+            self.stats.bad_length += 1;
+            self.drop_datagram(&key);
+            return None;
+        }
+
+        // the buffer grows to the end of the fragment, not just by its length
+        self.make_room(&key, end.saturating_sub(before));
+
+        let datagram = self
+            .pending
+            .entry(key)
+            .or_insert_with(|| PartialDatagram::new(ts));
+
+        if !more_fragments {
+            datagram.total_len = Some(end);
+        }
+
+        let overlapped = datagram.insert(offset, payload);
+        if overlapped == payload.len() && overlapped > 0 {
+            self.stats.duplicates += 1;
+        } else if overlapped > 0 {
+            self.stats.overlaps += 1;
+        }
+
+        let grown = datagram.data.len() - before;
+        let complete = datagram.is_complete();
+        self.buffered_bytes += grown;
+
+        if !complete {
+            return None;
+        }
+
+        let datagram = self.pending.remove(&key).unwrap();
+        self.buffered_bytes -= datagram.data.len();
+        self.stats.reassembled += 1;
+
+        Some(datagram.data)
+    }
+
+    pub fn stats(&self) -> FragmentStats {
+        FragmentStats {
+            pending: self.pending.len(),
+            buffered_bytes: self.buffered_bytes,
+            ..self.stats
+        }
+    }
+
+    fn drop_datagram(&mut self, key: &FragmentKey) {
+        if let Some(datagram) = self.pending.remove(key) {
+            self.buffered_bytes -= datagram.data.len();
+        }
+    }
+
+    // in capture time, so replayed fragments expire relative to each other
+    fn expire(&mut self, ts: Duration) {
+        if ts.checked_sub(self.last_sweep).unwrap_or_default() < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = ts;
+
+        let expired: Vec<FragmentKey> = self
+            .pending
+            .iter()
+            .filter(|(_, datagram)| {
+                ts.checked_sub(datagram.started).unwrap_or_default() > FRAGMENT_TIMEOUT
+            })
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            self.stats.timeouts += 1;
+            self.drop_datagram(&key);
+        }
+    }
+
+    // evicts the oldest other datagrams until `key` can grow by `len` bytes
+    fn make_room(&mut self, key: &FragmentKey, len: usize) {
+        loop {
+            let full = !self.pending.contains_key(key) && self.pending.len() >= MAX_DATAGRAMS;
+            if !full && self.buffered_bytes + len <= MAX_BUFFERED_BYTES {
+                return;
+            }
+
+            let oldest = self
+                .pending
+                .iter()
+                .filter(|(other, _)| *other != key)
+                .min_by_key(|(_, datagram)| datagram.started)
+                .map(|(key, _)| *key);
+
+            match oldest {
+                Some(oldest) => {
+                    self.stats.evicted += 1;
+                    self.drop_datagram(&oldest);
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+pub fn reassemble(
+    key: FragmentKey,
+    offset: usize,
+    more_fragments: bool,
+    payload: &[u8],
+    ts: Duration,
+) -> Option<Vec<u8>> {
+    REASSEMBLER
+        .lock()
+        .unwrap()
+        .add(key, offset, more_fragments, payload, ts)
+}
+
+pub fn fragment_stats() -> FragmentStats {
+    REASSEMBLER.lock().unwrap().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn key(id: u32) -> FragmentKey {
+        FragmentKey {
+            source: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            destination: IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+            id,
+            protocol: 17,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.add(key(1), 8, false, b"world", secs(1)), None);
+        let datagram = reassembler.add(key(1), 0, true, b"hello, #", secs(1));
+        assert_eq!(datagram.as_deref(), Some(&b"hello, #world"[..]));
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.fragments, 2);
+        assert_eq!(stats.reassembled, 1);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn overlapping_pair() {
+        let mut reassembler = Reassembler::new();
+
+        // the second fragment rewrites bytes 8..16, the first copy is kept
+        assert_eq!(
+            reassembler.add(key(1), 0, true, b"AAAAAAAAAAAAAAAA", secs(1)),
+            None
+        );
+        let datagram = reassembler.add(key(1), 8, false, b"BBBBBBBBBBBBBBBB", secs(1));
+        assert_eq!(datagram.as_deref(), Some(&b"AAAAAAAAAAAAAAAABBBBBBBB"[..]));
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.overlaps, 1);
+        assert_eq!(stats.duplicates, 0);
+        assert_eq!(stats.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.add(key(1), 0, true, b"AAAAAAAA", secs(1)), None);
+        assert_eq!(reassembler.add(key(1), 0, true, b"AAAAAAAA", secs(1)), None);
+        assert_eq!(reassembler.stats().duplicates, 1);
+        assert_eq!(reassembler.stats().buffered_bytes, 8);
+    }
+
+    #[test]
+    fn oversized() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.add(key(1), 0, true, &[0; 8], secs(1)), None);
+        assert_eq!(
+            reassembler.add(key(1), 65528, true, &[0; 16], secs(1)),
+            None
+        );
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.oversized, 1);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.add(key(1), 16, false, &[0; 8], secs(1)), None);
+        // a second last fragment ending elsewhere
+        assert_eq!(reassembler.add(key(1), 8, false, &[0; 8], secs(1)), None);
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.bad_length, 1);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn past_the_byte_budget() {
+        let mut reassembler = Reassembler::new();
+        let payload = vec![0; 60000];
+
+        // each datagram buffers 65000 bytes, 64 of them fit in 4MB
+        let datagrams = MAX_BUFFERED_BYTES / 65000 + 1;
+        for id in 0..datagrams as u32 {
+            let ts = secs(1) + Duration::from_millis(id as u64);
+            assert_eq!(reassembler.add(key(id), 5000, true, &payload, ts), None);
+            assert!(reassembler.stats().buffered_bytes <= MAX_BUFFERED_BYTES);
+        }
+
+        // the oldest made room for the last
+        let stats = reassembler.stats();
+        assert_eq!(stats.evicted, 1);
+        assert_eq!(stats.pending, datagrams - 1);
+        assert!(!reassembler.pending.contains_key(&key(0)));
+        assert!(reassembler.pending.contains_key(&key(datagrams as u32 - 1)));
+    }
+
+    #[test]
+    fn timeout() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.add(key(1), 0, true, &[0; 8], secs(1)), None);
+        assert_eq!(reassembler.add(key(2), 0, true, &[0; 8], secs(40)), None);
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.buffered_bytes, 8);
+    }
+}
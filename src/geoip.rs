@@ -6,11 +6,11 @@ use std::str::FromStr;
 
 use maxminddb;
 
+use super::config::CONFIG;
+
 lazy_static! {
-    static ref CITY_READER: Reader<Vec<u8>> =
-        Reader::open_readfile("geodata/mmdb/GeoLite2-City.mmdb").unwrap();
-    static ref ASN_READER: Reader<Vec<u8>> =
-        Reader::open_readfile("geodata/mmdb/GeoLite2-ASN.mmdb").unwrap();
+    static ref CITY_READER: Reader<Vec<u8>> = Reader::open_readfile(&CONFIG.geoip_city).unwrap();
+    static ref ASN_READER: Reader<Vec<u8>> = Reader::open_readfile(&CONFIG.geoip_asn).unwrap();
 }
 
 // TODO filter internal network ip address and do not panic here!
@@ -23,7 +23,7 @@ pub fn asn_lookup(ip: IpAddr) -> Result<Asn, MaxMindDBError> {
 }
 
 pub fn test_geo_lookup() {
-    let filename = "geodata/mmdb/GeoLite2-City.mmdb";
+    let filename = &CONFIG.geoip_city;
     let reader = Reader::open_readfile(filename).unwrap();
 
     let ip: IpAddr = FromStr::from_str("1.1.1.1").unwrap();
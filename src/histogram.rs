@@ -0,0 +1,100 @@
+use serde::Serialize;
+
+use std::time::Duration;
+
+/**
+ * A latency histogram with fixed millisecond buckets, shared by the
+ * modules that time handshakes and queries
+ */
+
+// bucket upper bounds in ms, the last bucket holds everything slower
+const LATENCY_BOUNDS: &[f64] = &[
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0,
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyHistogram {
+    pub bounds: &'static [f64],
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    // estimated from the buckets when the stats are requested
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            bounds: LATENCY_BOUNDS,
+            counts: vec![0; LATENCY_BOUNDS.len() + 1],
+            count: 0,
+            sum_ms: 0.0,
+            min_ms: 0.0,
+            max_ms: 0.0,
+            p50: 0.0,
+            p90: 0.0,
+            p99: 0.0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn add(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+
+        self.counts[bucket] += 1;
+        if self.count == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        if ms > self.max_ms {
+            self.max_ms = ms;
+        }
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+
+    // interpolates inside the bucket the percentile falls in
+    fn percentile(&self, p: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        let rank = p * self.count as f64;
+        let mut seen = 0.0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            let count = *count as f64;
+            if count > 0.0 && seen + count >= rank {
+                let lower = if bucket == 0 {
+                    self.min_ms
+                } else {
+                    LATENCY_BOUNDS[bucket - 1].max(self.min_ms)
+                };
+                let upper = LATENCY_BOUNDS
+                    .get(bucket)
+                    .cloned()
+                    .unwrap_or(self.max_ms)
+                    .min(self.max_ms);
+
+                return lower + (upper - lower) * (rank - seen) / count;
+            }
+            seen += count;
+        }
+
+        self.max_ms
+    }
+
+    pub fn update_percentiles(&mut self) {
+        self.p50 = self.percentile(0.5);
+        self.p90 = self.percentile(0.9);
+        self.p99 = self.percentile(0.99);
+    }
+}
@@ -0,0 +1,181 @@
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+
+/**
+ * This module strips link layer headers for the datalink types
+ * pcap hands us, see https://www.tcpdump.org/linktypes.html
+ */
+
+pub const DLT_NULL: i32 = 0;
+pub const DLT_EN10MB: i32 = 1;
+// DLT_RAW differs between platforms, 101 is what savefiles use
+pub const DLT_RAW: i32 = 12;
+pub const DLT_RAW_OPENBSD: i32 = 14;
+pub const LINKTYPE_RAW: i32 = 101;
+pub const DLT_LOOP: i32 = 108;
+pub const DLT_LINUX_SLL: i32 = 113;
+pub const DLT_IPV4: i32 = 228;
+pub const DLT_IPV6: i32 = 229;
+pub const DLT_LINUX_SLL2: i32 = 276;
+
+pub const ETHERTYPE_VLAN: EtherType = EtherType(0x8100);
+pub const ETHERTYPE_QINQ: EtherType = EtherType(0x88a8);
+// pre-standard QinQ outer tag
+pub const ETHERTYPE_QINQ_OLD: EtherType = EtherType(0x9100);
+pub const ETHERTYPE_MPLS: EtherType = EtherType(0x8847);
+pub const ETHERTYPE_MPLS_MCAST: EtherType = EtherType(0x8848);
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const MPLS_LABEL_LEN: usize = 4;
+// pseudowire control word in front of ethernet over mpls
+const MPLS_CONTROL_WORD_LEN: usize = 4;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const NULL_HEADER_LEN: usize = 4;
+
+// BSD address families, these vary between the BSDs for ipv6
+const AF_INET: u32 = 2;
+const AF_INET6_LINUX: u32 = 10;
+const AF_INET6_BSD: u32 = 24;
+const AF_INET6_FREEBSD: u32 = 28;
+const AF_INET6_DARWIN: u32 = 30;
+
+pub fn is_supported(linktype: i32) -> bool {
+    match linktype {
+        DLT_NULL | DLT_EN10MB | DLT_RAW | DLT_RAW_OPENBSD | LINKTYPE_RAW | DLT_LOOP
+        | DLT_LINUX_SLL | DLT_IPV4 | DLT_IPV6 | DLT_LINUX_SLL2 => true,
+        _ => false,
+    }
+}
+
+/// Maps a pcap DLT_ value to the LINKTYPE_ value written in savefiles
+pub fn to_linktype(dlt: i32) -> u32 {
+    match dlt {
+        DLT_RAW | DLT_RAW_OPENBSD => LINKTYPE_RAW as u32,
+        _ => dlt as u32,
+    }
+}
+
+/// Returns the ethertype of the frame's payload and the payload
+pub fn decode_frame(linktype: i32, frame: &[u8]) -> Option<(EtherType, &[u8])> {
+    match linktype {
+        DLT_EN10MB => decode_ethernet(frame),
+        DLT_LINUX_SLL => decode_sll(frame),
+        DLT_LINUX_SLL2 => decode_sll2(frame),
+        DLT_NULL => decode_null(frame, u32::from_ne_bytes),
+        DLT_LOOP => decode_null(frame, u32::from_be_bytes),
+        DLT_RAW | DLT_RAW_OPENBSD | LINKTYPE_RAW => decode_raw(frame),
+        DLT_IPV4 => Some((EtherTypes::Ipv4, frame)),
+        DLT_IPV6 => Some((EtherTypes::Ipv6, frame)),
+        _ => None,
+    }
+}
+
+pub fn decode_ethernet(frame: &[u8]) -> Option<(EtherType, &[u8])> {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return None;
+    }
+
+    let ether_type = EtherType(u16::from_be_bytes([frame[12], frame[13]]));
+    Some((ether_type, &frame[ETHERNET_HEADER_LEN..]))
+}
+
+// Linux "cooked" capture, used by the "any" device
+fn decode_sll(frame: &[u8]) -> Option<(EtherType, &[u8])> {
+    if frame.len() < SLL_HEADER_LEN {
+        return None;
+    }
+
+    let ether_type = EtherType(u16::from_be_bytes([frame[14], frame[15]]));
+    Some((ether_type, &frame[SLL_HEADER_LEN..]))
+}
+
+// v2 moves the protocol to the front and adds the interface index
+fn decode_sll2(frame: &[u8]) -> Option<(EtherType, &[u8])> {
+    if frame.len() < SLL2_HEADER_LEN {
+        return None;
+    }
+
+    let ether_type = EtherType(u16::from_be_bytes([frame[0], frame[1]]));
+    Some((ether_type, &frame[SLL2_HEADER_LEN..]))
+}
+
+// BSD loopback, the address family is host endian for NULL and big endian for LOOP
+fn decode_null(frame: &[u8], read_u32: fn([u8; 4]) -> u32) -> Option<(EtherType, &[u8])> {
+    if frame.len() < NULL_HEADER_LEN {
+        return None;
+    }
+
+    // host endian means the writer's host, a file may come from the other byte order
+    let family = read_u32([frame[0], frame[1], frame[2], frame[3]]);
+    let ether_type = null_family(family).or_else(|| null_family(family.swap_bytes()))?;
+
+    Some((ether_type, &frame[NULL_HEADER_LEN..]))
+}
+
+fn null_family(family: u32) -> Option<EtherType> {
+    match family {
+        AF_INET => Some(EtherTypes::Ipv4),
+        AF_INET6_LINUX | AF_INET6_BSD | AF_INET6_FREEBSD | AF_INET6_DARWIN => {
+            Some(EtherTypes::Ipv6)
+        }
+        _ => None,
+    }
+}
+
+// no link layer header, eg. tun and wireguard devices
+fn decode_raw(frame: &[u8]) -> Option<(EtherType, &[u8])> {
+    let version = frame.first()? >> 4;
+
+    match version {
+        4 => Some((EtherTypes::Ipv4, frame)),
+        6 => Some((EtherTypes::Ipv6, frame)),
+        _ => None,
+    }
+}
+
+/// Strips an 802.1Q tag, returns the vlan id and the encapsulated ethertype
+pub fn decode_vlan(payload: &[u8]) -> Option<(u16, EtherType, &[u8])> {
+    if payload.len() < VLAN_TAG_LEN {
+        return None;
+    }
+
+    // 3 bits priority, 1 bit drop eligible, 12 bits vlan id
+    let tci = u16::from_be_bytes([payload[0], payload[1]]);
+    let ether_type = EtherType(u16::from_be_bytes([payload[2], payload[3]]));
+
+    Some((tci & 0x0fff, ether_type, &payload[VLAN_TAG_LEN..]))
+}
+
+/// Strips an mpls label stack, returns the labels and the encapsulated ethertype
+pub fn decode_mpls(payload: &[u8]) -> Option<(Vec<u32>, EtherType, &[u8])> {
+    let mut labels = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let entry = payload.get(offset..offset + MPLS_LABEL_LEN)?;
+        offset += MPLS_LABEL_LEN;
+
+        // 20 bits label, 3 bits traffic class, 1 bit bottom of stack, 8 bits ttl
+        let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        labels.push(entry >> 12);
+
+        if entry & 0x100 != 0 {
+            break;
+        }
+    }
+
+    let rest = &payload[offset..];
+
+    // mpls doesn't say what it carries, guess from the first nibble
+    match rest.first()? >> 4 {
+        4 => Some((labels, EtherTypes::Ipv4, rest)),
+        6 => Some((labels, EtherTypes::Ipv6, rest)),
+        // ethernet pseudowire with a control word
+        0 => {
+            let (ether_type, inner) = decode_ethernet(rest.get(MPLS_CONTROL_WORD_LEN..)?)?;
+            Some((labels, ether_type, inner))
+        }
+        _ => None,
+    }
+}
@@ -1,10 +1,7 @@
-#![feature(drain_filter)]
 use websocket::message::OwnedMessage;
 use websocket::sender::Writer;
 use websocket::sync::Server;
 
-use std::env;
-
 use std::net::TcpStream;
 use std::sync::{Arc, RwLock};
 use std::thread;
@@ -16,18 +13,19 @@ mod dns;
 use dns::{parse_dns, reverse_lookup};
 
 mod tcp;
-use tcp::parse_tcp_payload;
 
 mod traceroute;
-use traceroute::{handle_echo_reply, handle_time_exceeded};
+use traceroute::{handle_echo_reply, handle_echo_request, handle_time_exceeded};
 
 use crossbeam::channel::{unbounded, Receiver};
 
+#[cfg(target_os = "macos")]
 mod processes;
+#[cfg(target_os = "macos")]
 use processes::netstats;
 
 mod structs;
-use structs::{ClientRequest, PacketInfo};
+use structs::{ClientRequest, PacketInfo, TunnelInfo};
 
 mod client_connection;
 use client_connection::handle_clients;
@@ -35,6 +33,28 @@ use client_connection::handle_clients;
 mod geoip;
 use geoip::{asn_lookup, city_lookup, test_lookups};
 
+mod config;
+use config::CONFIG;
+
+mod dns_cache;
+mod dns_stats;
+mod fingerprint;
+mod flows;
+mod fragments;
+mod histogram;
+mod link_layer;
+mod recorder;
+mod reverse_dns;
+mod tcp_health;
+mod tcp_state;
+mod tcp_stream;
+mod tls;
+mod tls_alert;
+mod tls_anomaly;
+mod tls_stats;
+mod tunnel;
+mod x509;
+
 mod packet_capture;
 use packet_capture::cap;
 
@@ -46,9 +66,10 @@ use std::convert::TryFrom;
  */
 
 fn main() {
-    let bind = env::args().nth(1).unwrap_or("127.0.0.1:3012".to_owned());
+    let bind = CONFIG.bind.clone();
 
     // Test experimentation
+    #[cfg(target_os = "macos")]
     netstats();
     // test_lookups();
 
@@ -78,7 +99,7 @@ fn spawn_broadcast(rx: Receiver<OwnedMessage>, clients: Arc<RwLock<Vec<Writer<Tc
             clients
                 .write()
                 .unwrap()
-                .drain_filter(|c| c.send_message(&message).is_err());
+                .retain_mut(|c| c.send_message(&message).is_ok());
         }
     });
 }
@@ -1,10 +1,10 @@
-use pcap::{Capture, Device};
+use pcap::{Capture, Device, Precision};
 use pnet::datalink::{self, NetworkInterface};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes};
 use pnet::packet::icmp::{echo_reply, echo_request, time_exceeded, IcmpPacket, IcmpTypes};
 use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
@@ -12,31 +12,53 @@ use pnet::packet::udp::UdpPacket;
 use pnet::packet::*;
 
 use super::{parse_dns, reverse_lookup};
-use super::{ClientRequest, PacketInfo};
+use super::{ClientRequest, PacketInfo, TunnelInfo};
 
 use dipstick::{stats_all, AtomicBucket, InputScope, Output, ScheduleFlush, Stream};
 use std::io;
 
-use super::parse_tcp_payload;
-use super::{handle_echo_reply, handle_time_exceeded};
+use super::{handle_echo_reply, handle_echo_request, handle_time_exceeded};
 
 use std::convert::TryFrom;
 
-use super::netstats;
+use super::config::{Backend, CONFIG};
 
 use websocket::message::OwnedMessage;
 use websocket::sender::Writer;
 use websocket::sync::Server;
 
 use std::net::IpAddr;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossbeam::channel::Sender;
+use super::dns::{send_response, DNS_PORT};
+use super::dns_cache::cache_answers;
+use super::dns_stats::{
+    end_all_queries, expire_queries, match_response, track_malformed, track_query,
+};
+use super::flows::{end_all_flows, expire_flows, track_flow, FlowKey};
+use super::fragments::{reassemble, FragmentKey};
+use super::link_layer::{
+    decode_frame, decode_mpls, decode_vlan, is_supported, to_linktype, DLT_EN10MB, ETHERTYPE_MPLS,
+    ETHERTYPE_MPLS_MCAST, ETHERTYPE_QINQ, ETHERTYPE_QINQ_OLD, ETHERTYPE_VLAN,
+};
+use super::recorder::Recorder;
+use super::tcp_state::{is_tracked, track_connection};
+use super::tcp_stream::reassemble_segment;
+use super::tunnel::{decode_geneve, decode_gre, decode_vxlan, GENEVE_PORT, VXLAN_PORT};
 
-const CAPTURE_TCP: bool = true;
-const DEBUG: bool = false;
-const STATS: bool = false;
+use crossbeam::channel::Sender;
 
-const PCAP: bool = false;
+// nested tunnels beyond this are treated as opaque traffic
+const MAX_TUNNEL_DEPTH: usize = 4;
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+// bounds the extension header walk for crafted packets
+const MAX_IPV6_EXTENSION_HEADERS: usize = 16;
+// longest a recording waits for a quiet link before it is flushed
+const RECORDER_TICK: Duration = Duration::from_secs(1);
+// how often live captures expire state while the link is quiet
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 fn is_local(ip: IpAddr) -> bool {
     let interfaces = pnet::datalink::interfaces();
@@ -50,43 +72,126 @@ fn is_local(ip: IpAddr) -> bool {
 }
 
 pub fn cap(tx: Sender<OwnedMessage>) {
+    if let Some(ref path) = CONFIG.read_file {
+        replay_file(path, &tx);
+        return;
+    }
+
     println!("Running pcap...");
     println!("Devices {:?}", Device::list());
 
-    let device = Device::lookup().unwrap();
-    println!("Default device {:?}", device);
-
-    let name = device.name.as_str();
+    let mut names = CONFIG.interfaces.clone();
+    if names.is_empty() {
+        let device = Device::lookup().unwrap();
+        println!("Default device {:?}", device);
+        names.push(device.name);
+    }
     // "any";
     // "lo0";
 
-    println!("Capturing on device {:?}", name);
+    // one capture thread per interface
+    let handles: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let tx = tx.clone();
+            thread::Builder::new()
+                .name(format!("capture-{}", name))
+                .spawn(move || {
+                    println!("Capturing on device {:?}", name);
 
-    let mut cap = Capture::from_device(name)
-        .unwrap()
-        .timeout(1)
-        .promisc(true)
-        // .snaplen(5000)
-        .open()
-        .unwrap();
+                    match CONFIG.backend {
+                        Backend::Pnet => capture_pnet(&name, &tx),
+                        Backend::Pcap => capture_pcap(&name, &tx),
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
 
-    // does a bpf filter
-    // cap.filter(&"udp").unwrap();
+    for handle in handles {
+        if handle.join().is_err() {
+            println!("Capture thread panicked");
+        }
+    }
+}
 
+fn setup_recorder(interface_name: &str, linktype: i32) -> Option<Recorder> {
+    let dir = CONFIG.record_dir.as_ref()?;
+
+    match Recorder::new(
+        dir,
+        interface_name,
+        to_linktype(linktype),
+        CONFIG.recorder_options(),
+    ) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            println!("Can't record to {}: {}", dir, e);
+            None
+        }
+    }
+}
+
+fn record(recorder: &mut Option<Recorder>, ts: Duration, len: u32, data: &[u8]) {
+    if let Some(r) = recorder.as_mut() {
+        if let Err(e) = r.write(ts, len, data) {
+            println!("Recording failed, stopping: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+// flushes frames buffered by the recorder while the link is quiet
+fn flush_recording(recorder: &mut Option<Recorder>) {
+    if let Some(r) = recorder.as_mut() {
+        if let Err(e) = r.tick() {
+            println!("Recording failed, stopping: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+// expires state that's normally swept when packets arrive, live captures only
+fn sweep_idle(last_sweep: &mut Instant, tx: &Sender<OwnedMessage>) {
+    if last_sweep.elapsed() < IDLE_SWEEP_INTERVAL {
+        return;
+    }
+    *last_sweep = Instant::now();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    expire_flows(now, tx);
+    expire_queries(now, tx);
+}
+
+fn capture_pnet(name: &str, tx: &Sender<OwnedMessage>) {
     use pnet::datalink::Channel::Ethernet;
 
+    if CONFIG.filter.is_some() {
+        println!("Warning: bpf filters are only supported with the pcap backend");
+    }
+
     let interface_names_match = |iface: &NetworkInterface| iface.name == name;
 
     // Find the network interface with the provided name
     let interfaces = datalink::interfaces();
-    let interface = interfaces
-        .into_iter()
-        .filter(interface_names_match)
-        .next()
-        .unwrap();
+    let interface = match interfaces.into_iter().filter(interface_names_match).next() {
+        Some(interface) => interface,
+        None => {
+            println!("No such interface {}", name);
+            return;
+        }
+    };
+
+    // wake up now and then so recordings get flushed on a quiet link
+    let config = datalink::Config {
+        read_timeout: Some(RECORDER_TICK),
+        ..Default::default()
+    };
 
     // Create a channel to receive on
-    let (_, ether_rx) = match datalink::channel(&interface, Default::default()) {
+    let (_, ether_rx) = match datalink::channel(&interface, config) {
         Ok(Ethernet(tx, rx)) => (tx, rx),
         Ok(_) => panic!("packetdump: unhandled channel type: {}"),
         Err(e) => panic!("packetdump: unable to create channel: {}", e),
@@ -94,27 +199,79 @@ pub fn cap(tx: Sender<OwnedMessage>) {
 
     let mut iter = ether_rx;
 
-    if !PCAP {
-        loop {
-            match iter.next() {
-                Ok(packet) => {
-                    let ether = EthernetPacket::new(packet).unwrap();
-                    handle_ethernet_packet(&ether, &tx);
-                }
-                Err(e) => panic!("packetdump: unable to receive packet: {}", e),
+    // pnet only hands out ethernet frames
+    let mut recorder = setup_recorder(name, DLT_EN10MB);
+    let mut last_sweep = Instant::now();
+
+    loop {
+        match iter.next() {
+            Ok(packet) => {
+                // pnet doesn't expose kernel timestamps
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+
+                record(&mut recorder, ts, packet.len() as u32, packet);
+
+                handle_frame(name, DLT_EN10MB, ts, packet, &tx);
             }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => panic!("packetdump: unable to receive packet: {}", e),
         }
+
+        flush_recording(&mut recorder);
+        sweep_idle(&mut last_sweep, tx);
+    }
+}
+
+fn capture_pcap(name: &str, tx: &Sender<OwnedMessage>) {
+    let cap = Capture::from_device(name).and_then(|device| {
+        device
+            .timeout(CONFIG.timeout)
+            .promisc(CONFIG.promisc)
+            .snaplen(CONFIG.snaplen)
+            .precision(LIVE_PRECISION)
+            .open()
+    });
+
+    let mut cap = match cap {
+        Ok(cap) => cap,
+        Err(e) => {
+            println!("Can't capture on {}: {}", name, e);
+            return;
+        }
+    };
+
+    // does a bpf filter
+    if let Some(ref filter) = CONFIG.filter {
+        println!("Using filter {:?}", filter);
+        if let Err(e) = cap.filter(filter) {
+            println!("Invalid filter {}: {}", filter, e);
+            return;
+        }
+    }
+
+    let linktype = cap.get_datalink().0;
+    println!("[{}]: Datalink type {}", name, linktype);
+    if !is_supported(linktype) {
+        println!(
+            "[{}]: Warning: unsupported datalink type {}",
+            name, linktype
+        );
     }
 
+    let mut recorder = setup_recorder(name, linktype);
+
     // set up metrics
     let bucket = AtomicBucket::new();
 
-    if STATS {
+    if CONFIG.stats {
         bucket.drain(Stream::to_stdout());
         bucket.flush_every(std::time::Duration::from_secs(1));
     }
 
     let mut i = 0;
+    let mut last_sweep = Instant::now();
 
     let bytes = bucket.counter("bytes: ");
     let packets = bucket.marker("packets: ");
@@ -122,109 +279,456 @@ pub fn cap(tx: Sender<OwnedMessage>) {
     // traceroute::test_ping();
     // traceroute::test_traceroute();
 
-    if PCAP {
-        loop {
-            i += 1;
-            match cap.next() {
-                Ok(packet) => {
-                    bytes.count(packet.len());
-                    packets.mark();
-                    // println!("received packet! {:?}", packet);
-                    let header = packet.header;
-                    if header.caplen != header.len {
-                        println!(
-                            "Warning bad packet.. len {}: caplen: {}, header len: {}",
-                            packet.len(),
-                            header.caplen,
-                            header.len
-                        );
-                    }
+    loop {
+        i += 1;
+        match cap.next() {
+            Ok(packet) => {
+                bytes.count(packet.len());
+                packets.mark();
+                // println!("received packet! {:?}", packet);
+                let header = packet.header;
+                if header.caplen != header.len {
+                    println!(
+                        "[{}]: Warning bad packet.. len {}: caplen: {}, header len: {}",
+                        name,
+                        packet.len(),
+                        header.caplen,
+                        header.len
+                    );
+                }
 
-                    // .ts
+                let ts = timeval_to_duration(&header.ts, LIVE_PRECISION);
 
-                    let ether = EthernetPacket::new(&packet).unwrap();
-                    handle_ethernet_packet(&ether, &tx);
-                }
-                Err(_) => {
-                    // println!("Error! {:?}", e);
-                }
+                record(&mut recorder, ts, header.len, &packet);
+
+                handle_frame(name, linktype, ts, &packet, &tx);
+            }
+            Err(_) => {
+                // println!("Error! {:?}", e);
+            }
+        }
+
+        flush_recording(&mut recorder);
+        sweep_idle(&mut last_sweep, tx);
+
+        let stats = cap.stats().unwrap();
+        if i % 10000 == 0 {
+            println!(
+                "[{}]: Stats: Received: {}, Dropped: {}, if_dropped: {}",
+                name, stats.received, stats.dropped, stats.if_dropped
+            );
+            bucket.stats(stats_all);
+            bucket.flush_to(&Stream::to_stdout().new_scope()).unwrap();
+        }
+    }
+}
+
+fn replay_file(path: &str, tx: &Sender<OwnedMessage>) {
+    println!("Replaying {} at speed {}", path, CONFIG.replay_speed);
+
+    let mut cap = match Capture::from_file_with_precision(path, Precision::Nano) {
+        Ok(cap) => cap,
+        Err(e) => {
+            println!("Can't open capture file {}: {}", path, e);
+            return;
+        }
+    };
+
+    if let Some(ref filter) = CONFIG.filter {
+        println!("Using filter {:?}", filter);
+        if let Err(e) = cap.filter(filter) {
+            println!("Invalid filter {}: {}", filter, e);
+            return;
+        }
+    }
+
+    let linktype = cap.get_datalink().0;
+    if !is_supported(linktype) {
+        println!("Warning: unsupported datalink type {}", linktype);
+    }
+
+    // packets are attributed to the file they came from
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+
+    let mut pacer = Pacer::new(CONFIG.replay_speed);
+    let mut count = 0;
+
+    loop {
+        match cap.next() {
+            Ok(packet) => {
+                count += 1;
+                let ts = timeval_to_duration(&packet.header.ts, Precision::Nano);
+                pacer.wait(ts);
+
+                handle_frame(name, linktype, ts, &packet, &tx);
+            }
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => {
+                println!("Error reading {}: {}", path, e);
+                break;
             }
+        }
+    }
+
+    println!("Replay of {} finished, {} packets", path, count);
+
+    // nothing more will arrive to expire what's left
+    end_all_flows("capture_end", tx);
+    end_all_queries(tx);
+}
+
+// pcap asks for nanoseconds without checking the device can deliver them,
+// so live captures stay with microseconds which every device has
+const LIVE_PRECISION: Precision = Precision::Micro;
+
+/// Time since the unix epoch, tv_usec holds nanoseconds with nano precision
+fn timeval_to_duration(ts: &libc::timeval, precision: Precision) -> Duration {
+    let subsec_nanos = match precision {
+        Precision::Nano => ts.tv_usec as u32,
+        Precision::Micro => ts.tv_usec as u32 * 1000,
+    };
+
+    Duration::new(ts.tv_sec as u64, subsec_nanos)
+}
+
+/// Sleeps between replayed packets to follow their original timing
+struct Pacer {
+    speed: f64,
+    // first packet's capture time and the wall clock time it was replayed at
+    start: Option<(Duration, Instant)>,
+}
+
+impl Pacer {
+    fn new(speed: f64) -> Self {
+        Self { speed, start: None }
+    }
+
+    fn wait(&mut self, ts: Duration) {
+        if self.speed <= 0.0 {
+            return;
+        }
+
+        let (first_ts, started) = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some((ts, Instant::now()));
+                return;
+            }
+        };
 
-            let stats = cap.stats().unwrap();
-            if i % 10000 == 0 {
+        // out of order timestamps are replayed immediately
+        if ts <= first_ts {
+            return;
+        }
+
+        let offset = (ts - first_ts).as_secs_f64() / self.speed;
+        let due = started + Duration::from_secs_f64(offset);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+/// Context gathered while walking down the layers of a packet
+#[derive(Clone)]
+pub struct PacketMeta<'a> {
+    pub interface: &'a str,
+    // capture time since the unix epoch
+    pub ts: Duration,
+    // 802.1Q/802.1ad tags, outermost first
+    pub vlans: Vec<u16>,
+    // mpls label stack, top first
+    pub mpls: Vec<u32>,
+    // decapsulated tunnels, outermost first
+    pub tunnels: Vec<TunnelInfo>,
+}
+
+impl<'a> PacketMeta<'a> {
+    fn new(interface: &'a str, ts: Duration) -> Self {
+        Self {
+            interface,
+            ts,
+            vlans: Vec::new(),
+            mpls: Vec::new(),
+            tunnels: Vec::new(),
+        }
+    }
+}
+
+fn handle_frame(
+    interface_name: &str,
+    linktype: i32,
+    ts: Duration,
+    frame: &[u8],
+    tx: &Sender<OwnedMessage>,
+) {
+    match decode_frame(linktype, frame) {
+        Some((ether_type, payload)) => {
+            let mut meta = PacketMeta::new(interface_name, ts);
+            handle_ether_type(&mut meta, ether_type, payload, tx)
+        }
+        None => {
+            if CONFIG.debug {
                 println!(
-                    "Stats: Received: {}, Dropped: {}, if_dropped: {}",
-                    stats.received, stats.dropped, stats.if_dropped
+                    "[{}]: Can't decode frame, linktype: {}, length: {}",
+                    interface_name,
+                    linktype,
+                    frame.len()
                 );
-                bucket.stats(stats_all);
-                bucket.flush_to(&Stream::to_stdout().new_scope()).unwrap();
             }
         }
     }
 }
 
-fn handle_ethernet_packet(ether: &EthernetPacket, tx: &Sender<OwnedMessage>) {
-    let ether_type = ether.get_ethertype();
+fn handle_ether_type(
+    meta: &mut PacketMeta,
+    ether_type: EtherType,
+    payload: &[u8],
+    tx: &Sender<OwnedMessage>,
+) {
+    let mut ether_type = ether_type;
+    let mut payload = payload;
+
+    // peel off vlan tags and mpls labels
+    loop {
+        let decap = match ether_type {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD => {
+                decode_vlan(payload).map(|(vlan, inner, rest)| {
+                    meta.vlans.push(vlan);
+                    (inner, rest)
+                })
+            }
+            ETHERTYPE_MPLS | ETHERTYPE_MPLS_MCAST => {
+                decode_mpls(payload).map(|(labels, inner, rest)| {
+                    meta.mpls.extend(labels);
+                    (inner, rest)
+                })
+            }
+            _ => break,
+        };
+
+        match decap {
+            Some((inner, rest)) => {
+                ether_type = inner;
+                payload = rest;
+            }
+            None => {
+                if CONFIG.debug {
+                    println!("[{}]: Malformed {:?} header", meta.interface, ether_type);
+                }
+                return;
+            }
+        }
+    }
 
     match ether_type {
         EtherTypes::Ipv4 => {
             // print!("IPV4 ");
-            handle_ipv4_packet("meow", &ether, &tx);
+            handle_ipv4_packet(meta, payload, &tx);
         }
         EtherTypes::Ipv6 => {
             // print!("IPV6 ");
-            handle_ipv6_packet("woof", &ether, &tx);
+            handle_ipv6_packet(meta, payload, &tx);
         }
         EtherTypes::Arp => {
             // println!("ARP");
         }
         _ => {
             // 	println!(
-            // 	"Unknown packet: {} > {}; ethertype: {:?}",
-            // 	ether.get_source(),
-            // 	ether.get_destination(),
-            // 	ether.get_ethertype()
+            // 	"Unknown packet: ethertype: {:?}",
+            // 	ether_type
             // )
         }
     }
 }
 
-fn handle_ipv4_packet(interface_name: &str, ethernet: &EthernetPacket, tx: &Sender<OwnedMessage>) {
-    let header = Ipv4Packet::new(ethernet.payload());
+fn handle_ipv4_packet(meta: &PacketMeta, packet: &[u8], tx: &Sender<OwnedMessage>) {
+    let header = Ipv4Packet::new(packet);
     if let Some(header) = header {
         // println!("TTL {}", header.get_ttl());
 
-        handle_transport_protocol(
-            interface_name,
-            IpAddr::V4(header.get_source()),
-            IpAddr::V4(header.get_destination()),
-            header.get_next_level_protocol(),
-            header.payload(),
-            tx,
-        );
+        let source = IpAddr::V4(header.get_source());
+        let destination = IpAddr::V4(header.get_destination());
+        let protocol = header.get_next_level_protocol();
+
+        let more_fragments = header.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let offset = header.get_fragment_offset() as usize * 8;
+
+        if more_fragments || offset > 0 {
+            let key = FragmentKey {
+                source,
+                destination,
+                id: header.get_identification() as u32,
+                protocol: protocol.0,
+            };
+
+            if let Some(datagram) =
+                reassemble(key, offset, more_fragments, header.payload(), meta.ts)
+            {
+                handle_transport_protocol(meta, source, destination, protocol, &datagram, tx);
+            }
+            return;
+        }
+
+        handle_transport_protocol(meta, source, destination, protocol, header.payload(), tx);
     } else {
-        println!("[{}]: Malformed IPv4 Packet", interface_name);
+        println!("[{}]: Malformed IPv4 Packet", meta.interface);
     }
 }
 
-fn handle_ipv6_packet(interface_name: &str, ethernet: &EthernetPacket, tx: &Sender<OwnedMessage>) {
-    let header = Ipv6Packet::new(ethernet.payload());
+fn handle_ipv6_packet(meta: &PacketMeta, packet: &[u8], tx: &Sender<OwnedMessage>) {
+    let header = Ipv6Packet::new(packet);
     if let Some(header) = header {
-        handle_transport_protocol(
-            interface_name,
-            IpAddr::V6(header.get_source()),
-            IpAddr::V6(header.get_destination()),
+        let source = IpAddr::V6(header.get_source());
+        let destination = IpAddr::V6(header.get_destination());
+
+        handle_ipv6_payload(
+            meta,
+            source,
+            destination,
             header.get_next_header(),
             header.payload(),
+            MAX_IPV6_EXTENSION_HEADERS,
             tx,
         );
     } else {
-        println!("[{}]: Malformed IPv6 Packet", interface_name);
+        println!("[{}]: Malformed IPv6 Packet", meta.interface);
+    }
+}
+
+/// Walks the extension header chain until the upper layer protocol is found,
+/// `budget` is how many more extension headers may be walked, fragments included
+fn handle_ipv6_payload(
+    meta: &PacketMeta,
+    source: IpAddr,
+    destination: IpAddr,
+    next_header: IpNextHeaderProtocol,
+    payload: &[u8],
+    budget: usize,
+    tx: &Sender<OwnedMessage>,
+) {
+    let mut next_header = next_header;
+    let mut payload = payload;
+    let mut budget = budget;
+
+    loop {
+        let header_len = match next_header {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts
+            | IpNextHeaderProtocols::MobilityHeader
+            | IpNextHeaderProtocols::Hip
+            | IpNextHeaderProtocols::Shim6 => {
+                // length in 8 octet units, not counting the first 8
+                payload.get(1).map(|&len| (len as usize + 1) * 8)
+            }
+            IpNextHeaderProtocols::Ah => {
+                // length in 4 octet units, minus 2
+                payload.get(1).map(|&len| (len as usize + 2) * 4)
+            }
+            IpNextHeaderProtocols::Ipv6Frag => {
+                if budget == 0 {
+                    break;
+                }
+                handle_ipv6_fragment(meta, source, destination, payload, budget - 1, tx);
+                return;
+            }
+            IpNextHeaderProtocols::Esp => {
+                // everything after the spi and sequence number is encrypted
+                if CONFIG.debug && payload.len() >= 4 {
+                    println!(
+                        "[{}]: ESP Packet {} > {}; spi: {:#010x}",
+                        meta.interface,
+                        source,
+                        destination,
+                        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                    );
+                }
+                return;
+            }
+            IpNextHeaderProtocols::Ipv6NoNxt => return,
+            _ => {
+                handle_transport_protocol(meta, source, destination, next_header, payload, tx);
+                return;
+            }
+        };
+
+        // only the extension headers count, the upper layer is always handled
+        if budget == 0 {
+            break;
+        }
+        budget -= 1;
+
+        match header_len {
+            Some(header_len) if header_len <= payload.len() => {
+                next_header = IpNextHeaderProtocol(payload[0]);
+                payload = &payload[header_len..];
+            }
+            _ => {
+                println!(
+                    "[{}]: Malformed IPv6 extension header {:?}",
+                    meta.interface, next_header
+                );
+                return;
+            }
+        }
+    }
+
+    if CONFIG.debug {
+        println!(
+            "[{}]: Too many IPv6 extension headers {} > {}",
+            meta.interface, source, destination
+        );
+    }
+}
+
+fn handle_ipv6_fragment(
+    meta: &PacketMeta,
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+    budget: usize,
+    tx: &Sender<OwnedMessage>,
+) {
+    // next header, reserved, 13 bits offset + 2 reserved bits + M flag, identification
+    if packet.len() < IPV6_FRAGMENT_HEADER_LEN {
+        println!("[{}]: Malformed IPv6 Fragment header", meta.interface);
+        return;
+    }
+
+    let protocol = IpNextHeaderProtocol(packet[0]);
+    let offset_flags = u16::from_be_bytes([packet[2], packet[3]]);
+    let id = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+
+    let key = FragmentKey {
+        source,
+        destination,
+        id,
+        protocol: protocol.0,
+    };
+
+    let offset = (offset_flags >> 3) as usize * 8;
+    let more_fragments = offset_flags & 1 != 0;
+    let payload = &packet[IPV6_FRAGMENT_HEADER_LEN..];
+
+    // atomic fragments (RFC 6946) don't need reassembly
+    if offset == 0 && !more_fragments {
+        handle_ipv6_payload(meta, source, destination, protocol, payload, budget, tx);
+        return;
+    }
+
+    // the fragmentable part may start with more extension headers
+    if let Some(datagram) = reassemble(key, offset, more_fragments, payload, meta.ts) {
+        handle_ipv6_payload(meta, source, destination, protocol, &datagram, budget, tx);
     }
 }
 
 fn handle_udp_packet(
-    interface_name: &str,
+    meta: &PacketMeta,
     source: IpAddr,
     destination: IpAddr,
     packet: &[u8],
@@ -233,6 +737,10 @@ fn handle_udp_packet(
     let udp = UdpPacket::new(packet);
 
     if let Some(udp) = udp {
+        if CONFIG.dissectors.tunnels && handle_udp_tunnel(meta, source, destination, &udp, tx) {
+            return;
+        }
+
         let packet_info = PacketInfo {
             len: udp.get_length(),
             dest: destination.to_string(),
@@ -240,15 +748,37 @@ fn handle_udp_packet(
             dest_port: udp.get_destination(),
             src_port: udp.get_source(),
             t: String::from("u"),
+            interface: meta.interface.to_owned(),
+            vlans: meta.vlans.clone(),
+            mpls: meta.mpls.clone(),
+            tunnels: meta.tunnels.clone(),
+            ts: meta.ts,
         };
 
         let payload = serde_json::to_string(&packet_info).unwrap();
         tx.send(OwnedMessage::Text(payload)).unwrap();
 
-        if DEBUG {
+        let key = FlowKey::new(
+            IpNextHeaderProtocols::Udp.0,
+            (source, udp.get_source()),
+            (destination, udp.get_destination()),
+            meta.vlans.last().cloned(),
+            meta.interface,
+        );
+
+        track_flow(
+            &key,
+            (source, udp.get_source()),
+            (destination, udp.get_destination()),
+            packet.len(),
+            meta.ts,
+            tx,
+        );
+
+        if CONFIG.debug {
             println!(
                 "[{}]: UDP Packet: {}:{} > {}:{}; length: {}",
-                interface_name,
+                meta.interface,
                 source,
                 udp.get_source(),
                 destination,
@@ -260,22 +790,40 @@ fn handle_udp_packet(
         // start parsing
         let payload = udp.payload();
 
-        if udp.get_source() == 53 {
+        if CONFIG.dissectors.dns
+            && (udp.get_source() == DNS_PORT || udp.get_destination() == DNS_PORT)
+        {
+            let source = (source, udp.get_source());
+            let destination = (destination, udp.get_destination());
+
             // println!("Payload {:?}", payload);
             parse_dns(payload).map(|v| {
                 // println!("DNS {}\n", v);
-                v.parse_body();
+                match v.parse_body() {
+                    Ok(ref message) if message.is_reply() => {
+                        let latency = match_response(message, source, destination, meta.ts, tx);
+                        cache_answers(message, meta.ts);
+                        send_response(message, source, destination, latency, meta.ts, tx);
+                    }
+                    Ok(ref message) => track_query(message, source, destination, meta.ts, tx),
+                    Err(e) => {
+                        track_malformed(source, destination, meta.ts);
+                        if CONFIG.debug {
+                            println!("Bad DNS packet from {:?}: {}", source, e);
+                        }
+                    }
+                }
             });
         }
 
     // println!("UDP Payload {:?}", udp.payload());
     } else {
-        println!("[{}]: Malformed UDP Packet", interface_name);
+        println!("[{}]: Malformed UDP Packet", meta.interface);
     }
 }
 
 fn handle_tcp_packet(
-    interface_name: &str,
+    meta: &PacketMeta,
     source: IpAddr,
     destination: IpAddr,
     packet: &[u8],
@@ -283,10 +831,10 @@ fn handle_tcp_packet(
 ) {
     let tcp = TcpPacket::new(packet);
     if let Some(tcp) = tcp {
-        if DEBUG {
+        if CONFIG.debug {
             println!(
                 "[{}]: TCP Packet: {}:{} > {}:{}; length: {}",
-                interface_name,
+                meta.interface,
                 source,
                 tcp.get_source(),
                 destination,
@@ -295,29 +843,38 @@ fn handle_tcp_packet(
             );
         }
 
-        // generate a key is uniquely id the 5 tuple
-        let key = match source < destination {
-            // is_local(source)
-            true => format!(
-                "tcp_{}:{}_{}:{}",
-                source,
-                tcp.get_source(),
-                destination,
-                tcp.get_destination()
-            ),
-            false => format!(
-                "tcp_{}:{}_{}:{}",
-                destination,
-                tcp.get_destination(),
-                source,
-                tcp.get_source()
-            ),
-        };
+        let key = FlowKey::new(
+            IpNextHeaderProtocols::Tcp.0,
+            (source, tcp.get_source()),
+            (destination, tcp.get_destination()),
+            meta.vlans.last().cloned(),
+            meta.interface,
+        );
+
+        // stray resets and acks, or segments after a close, don't start flows
+        if is_tracked(&key, &tcp, meta.ts) {
+            track_flow(
+                &key,
+                (source, tcp.get_source()),
+                (destination, tcp.get_destination()),
+                packet.len(),
+                meta.ts,
+                tx,
+            );
+
+            // before the state machine, which forgets the stream once the connection closes
+            reassemble_segment(&key, (source, tcp.get_source()), &tcp, meta.ts, tx);
+
+            track_connection(
+                &key,
+                (source, tcp.get_source()),
+                (destination, tcp.get_destination()),
+                &tcp,
+                meta.ts,
+                tx,
+            );
+        }
 
-        // tcp.get_source()
-        // tcp.get_destination()
-        // tcp.get_acknowledgement()
-        // get_sequence
         // options raw
         // get window
 
@@ -328,22 +885,22 @@ fn handle_tcp_packet(
             dest_port: tcp.get_destination(),
             src_port: tcp.get_source(),
             t: String::from("t"),
+            interface: meta.interface.to_owned(),
+            vlans: meta.vlans.clone(),
+            mpls: meta.mpls.clone(),
+            tunnels: meta.tunnels.clone(),
+            ts: meta.ts,
         };
 
         let payload = serde_json::to_string(&packet_info).unwrap();
         tx.send(OwnedMessage::Text(payload)).unwrap();
-
-        // strip tcp headers
-        let packet = tcp.payload();
-
-        parse_tcp_payload(packet, &key);
     } else {
-        println!("[{}]: Malformed TCP Packet", interface_name);
+        println!("[{}]: Malformed TCP Packet", meta.interface);
     }
 }
 
 fn handle_transport_protocol(
-    interface_name: &str,
+    meta: &PacketMeta,
     source: IpAddr,
     destination: IpAddr,
     protocol: IpNextHeaderProtocol,
@@ -354,23 +911,39 @@ fn handle_transport_protocol(
 
     match protocol {
         IpNextHeaderProtocols::Udp => {
-            handle_udp_packet(interface_name, source, destination, packet, tx)
+            if CONFIG.dissectors.udp {
+                handle_udp_packet(meta, source, destination, packet, tx)
+            }
         }
         IpNextHeaderProtocols::Tcp => {
-            if CAPTURE_TCP {
-                handle_tcp_packet(interface_name, source, destination, packet, tx)
+            if CONFIG.dissectors.tcp {
+                handle_tcp_packet(meta, source, destination, packet, tx)
             }
         }
         IpNextHeaderProtocols::Icmp => {
-            handle_icmp_packet(interface_name, source, destination, packet)
+            if CONFIG.dissectors.icmp {
+                handle_icmp_packet(meta, source, destination, packet)
+            }
         }
         IpNextHeaderProtocols::Icmpv6 => {
-            handle_icmpv6_packet(interface_name, source, destination, packet)
+            if CONFIG.dissectors.icmp {
+                handle_icmpv6_packet(meta, source, destination, packet)
+            }
+        }
+        IpNextHeaderProtocols::Gre => {
+            if CONFIG.dissectors.tunnels {
+                handle_gre_packet(meta, source, destination, packet, tx)
+            }
+        }
+        IpNextHeaderProtocols::Ipv4 | IpNextHeaderProtocols::Ipv6 => {
+            if CONFIG.dissectors.tunnels {
+                handle_ip_in_ip(meta, source, destination, protocol, packet, tx)
+            }
         }
         _ => {
             /*println!(
                 "[{}]: Unknown {} packet: {} > {}; protocol: {:?} length: {}",
-                interface_name,
+                meta.interface,
                 match source {
                     IpAddr::V4(..) => "IPv4",
                     _ => "IPv6",
@@ -384,7 +957,106 @@ fn handle_transport_protocol(
     }
 }
 
-fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+/// Starts a new layer of dissection for traffic found inside a tunnel
+fn enter_tunnel<'a>(
+    meta: &PacketMeta<'a>,
+    kind: &str,
+    source: IpAddr,
+    destination: IpAddr,
+    id: Option<u32>,
+) -> Option<PacketMeta<'a>> {
+    if meta.tunnels.len() >= MAX_TUNNEL_DEPTH {
+        if CONFIG.debug {
+            println!(
+                "[{}]: Too many nested tunnels, not decapsulating {} {} > {}",
+                meta.interface, kind, source, destination
+            );
+        }
+        return None;
+    }
+
+    let mut inner = meta.clone();
+    inner.tunnels.push(TunnelInfo {
+        kind: kind.to_owned(),
+        src: source,
+        dest: destination,
+        id,
+    });
+
+    Some(inner)
+}
+
+fn handle_gre_packet(
+    meta: &PacketMeta,
+    source: IpAddr,
+    destination: IpAddr,
+    packet: &[u8],
+    tx: &Sender<OwnedMessage>,
+) {
+    match decode_gre(packet) {
+        Some(gre) => {
+            if let Some(mut inner) = enter_tunnel(meta, "gre", source, destination, gre.id) {
+                handle_ether_type(&mut inner, gre.ether_type, gre.payload, tx);
+            }
+        }
+        None => {
+            if CONFIG.debug {
+                println!("[{}]: Unsupported GRE Packet", meta.interface);
+            }
+        }
+    }
+}
+
+fn handle_ip_in_ip(
+    meta: &PacketMeta,
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    packet: &[u8],
+    tx: &Sender<OwnedMessage>,
+) {
+    if let Some(inner) = enter_tunnel(meta, "ipip", source, destination, None) {
+        match protocol {
+            IpNextHeaderProtocols::Ipv4 => handle_ipv4_packet(&inner, packet, tx),
+            _ => handle_ipv6_packet(&inner, packet, tx),
+        }
+    }
+}
+
+/// Returns true when the datagram was a vxlan or geneve tunnel
+fn handle_udp_tunnel(
+    meta: &PacketMeta,
+    source: IpAddr,
+    destination: IpAddr,
+    udp: &UdpPacket,
+    tx: &Sender<OwnedMessage>,
+) -> bool {
+    let (kind, decapsulated) = match udp.get_destination() {
+        VXLAN_PORT => ("vxlan", decode_vxlan(udp.payload())),
+        GENEVE_PORT => ("geneve", decode_geneve(udp.payload())),
+        _ => return false,
+    };
+
+    let decapsulated = match decapsulated {
+        Some(decapsulated) => decapsulated,
+        None => return false,
+    };
+
+    match enter_tunnel(meta, kind, source, destination, decapsulated.id) {
+        Some(mut inner) => {
+            handle_ether_type(
+                &mut inner,
+                decapsulated.ether_type,
+                decapsulated.payload,
+                tx,
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+fn handle_icmp_packet(meta: &PacketMeta, source: IpAddr, destination: IpAddr, packet: &[u8]) {
     let icmp_packet = IcmpPacket::new(packet);
     if let Some(icmp_packet) = icmp_packet {
         let icmp_payload = icmp_packet.payload();
@@ -392,10 +1064,10 @@ fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
         match icmp_packet.get_icmp_type() {
             IcmpTypes::EchoReply => {
                 let echo_reply_packet = echo_reply::EchoReplyPacket::new(packet).unwrap();
-                if DEBUG {
+                if CONFIG.debug {
                     println!(
                         "[{}]: ICMP echo reply {} -> {} (seq={:?}, id={:?})",
-                        interface_name,
+                        meta.interface,
                         source,
                         destination,
                         echo_reply_packet.get_sequence_number(),
@@ -403,14 +1075,14 @@ fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
                     );
                 }
 
-                handle_echo_reply(source, echo_reply_packet);
+                handle_echo_reply(source, echo_reply_packet, meta.ts);
             }
             IcmpTypes::EchoRequest => {
                 let echo_request_packet = echo_request::EchoRequestPacket::new(packet).unwrap();
-                if DEBUG {
+                if CONFIG.debug {
                     println!(
                         "[{}]: ICMP echo request {} -> {} (seq={:?}, id={:?})",
-                        interface_name,
+                        meta.interface,
                         source,
                         destination,
                         echo_request_packet.get_sequence_number(),
@@ -418,13 +1090,16 @@ fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
                         // echo_request_packet.payload(),
                     );
                 }
+
+                // our own probes on the wire give a more accurate send time
+                handle_echo_request(echo_request_packet, meta.ts);
             }
             IcmpTypes::TimeExceeded => {
                 let time_exceeded_packet = time_exceeded::TimeExceededPacket::new(packet).unwrap();
-                if DEBUG {
+                if CONFIG.debug {
                     println!(
                         "[{}]: ICMP TimeExceeded {} -> {} (seq={:?}, payload={:?})\n{:?}",
-                        interface_name,
+                        meta.interface,
                         source,
                         destination,
                         time_exceeded_packet,
@@ -433,35 +1108,35 @@ fn handle_icmp_packet(interface_name: &str, source: IpAddr, destination: IpAddr,
                     );
                 }
 
-                handle_time_exceeded(source, time_exceeded_packet);
+                handle_time_exceeded(source, time_exceeded_packet, meta.ts);
             }
             // TODO Add Destination unavailable
             _ => println!(
                 "[{}]: ICMP packet {} -> {} (type={:?})",
-                interface_name,
+                meta.interface,
                 source,
                 destination,
                 icmp_packet.get_icmp_type()
             ),
         }
     } else {
-        println!("[{}]: Malformed ICMP Packet", interface_name);
+        println!("[{}]: Malformed ICMP Packet", meta.interface);
     }
 }
 
-fn handle_icmpv6_packet(interface_name: &str, source: IpAddr, destination: IpAddr, packet: &[u8]) {
+fn handle_icmpv6_packet(meta: &PacketMeta, source: IpAddr, destination: IpAddr, packet: &[u8]) {
     let icmpv6_packet = Icmpv6Packet::new(packet);
     if let Some(icmpv6_packet) = icmpv6_packet {
-        if DEBUG {
+        if CONFIG.debug {
             println!(
                 "[{}]: ICMPv6 packet {} -> {} (type={:?})",
-                interface_name,
+                meta.interface,
                 source,
                 destination,
                 icmpv6_packet.get_icmpv6_type()
             )
         }
     } else {
-        println!("[{}]: Malformed ICMPv6 Packet", interface_name);
+        println!("[{}]: Malformed ICMPv6 Packet", meta.interface);
     }
 }
@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/**
+ * This module writes raw frames into a ring of pcap savefiles,
+ * so odd traffic seen on the radar can be opened in wireshark later
+ */
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const FILE_PREFIX: &str = "radar-";
+const FILE_SUFFIX: &str = ".pcap";
+
+// how often buffered packets are pushed to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct RecorderOptions {
+    // rotate after this many bytes have been written
+    pub max_bytes: u64,
+    // rotate after a file has been open this long
+    pub max_age: Option<Duration>,
+    // number of savefiles to keep, oldest are deleted first
+    pub max_files: usize,
+    pub snaplen: u32,
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    // radar-{interface}-
+    prefix: String,
+    linktype: u32,
+    options: RecorderOptions,
+
+    file: Option<BufWriter<File>>,
+    file_bytes: u64,
+    opened: Instant,
+    last_flush: Instant,
+    // frames written since the last flush
+    unflushed: bool,
+    seq: u32,
+
+    // files written, oldest first
+    files: VecDeque<PathBuf>,
+}
+
+impl Recorder {
+    pub fn new(
+        dir: &str,
+        interface_name: &str,
+        linktype: u32,
+        options: RecorderOptions,
+    ) -> io::Result<Recorder> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+
+        let prefix = format!("{}{}-", FILE_PREFIX, sanitize(interface_name));
+
+        // recordings from earlier runs count towards retention
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| is_recording(path, &prefix))
+            .collect();
+        files.sort();
+
+        Ok(Recorder {
+            dir,
+            prefix,
+            linktype,
+            options,
+            file: None,
+            file_bytes: 0,
+            opened: Instant::now(),
+            last_flush: Instant::now(),
+            unflushed: false,
+            seq: 0,
+            files: files.into_iter().collect(),
+        })
+    }
+
+    /// Appends a frame, `ts` is the time since the unix epoch
+    pub fn write(&mut self, ts: Duration, orig_len: u32, data: &[u8]) -> io::Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+
+        let caplen = std::cmp::min(data.len() as u32, self.options.snaplen);
+
+        let file = self.file.as_mut().unwrap();
+        file.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        file.write_all(&ts.subsec_micros().to_le_bytes())?;
+        file.write_all(&caplen.to_le_bytes())?;
+        file.write_all(&orig_len.to_le_bytes())?;
+        file.write_all(&data[..caplen as usize])?;
+
+        self.file_bytes += 16 + caplen as u64;
+        self.unflushed = true;
+
+        self.tick()
+    }
+
+    /// Pushes buffered frames to disk once they are FLUSH_INTERVAL old,
+    /// capture loops call this between packets so quiet links get flushed too
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.unflushed && self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        self.unflushed = false;
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        if self.file.is_none() {
+            return true;
+        }
+
+        if self.file_bytes >= self.options.max_bytes {
+            return true;
+        }
+
+        match self.options.max_age {
+            Some(max_age) => self.opened.elapsed() >= max_age,
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.seq += 1;
+
+        let path = self.dir.join(format!(
+            "{}{:010}-{:04}{}",
+            self.prefix,
+            now.as_secs(),
+            self.seq,
+            FILE_SUFFIX
+        ));
+
+        let mut file = BufWriter::new(File::create(&path)?);
+
+        // pcap global header
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?; // major version
+        file.write_all(&4u16.to_le_bytes())?; // minor version
+        file.write_all(&0i32.to_le_bytes())?; // thiszone
+        file.write_all(&0u32.to_le_bytes())?; // sigfigs
+        file.write_all(&self.options.snaplen.to_le_bytes())?;
+        file.write_all(&self.linktype.to_le_bytes())?;
+
+        println!("Recording to {:?}", path);
+
+        self.file = Some(file);
+        self.file_bytes = 24;
+        self.opened = Instant::now();
+        self.files.push_back(path);
+
+        self.enforce_retention();
+
+        Ok(())
+    }
+
+    fn enforce_retention(&mut self) {
+        while self.files.len() > self.options.max_files {
+            if let Some(oldest) = self.files.pop_front() {
+                if let Err(e) = fs::remove_file(&oldest) {
+                    println!("Can't remove old recording {:?}: {}", oldest, e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Can't flush recording: {}", e);
+        }
+    }
+}
+
+fn is_recording(path: &Path, prefix: &str) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with(prefix) && name.ends_with(FILE_SUFFIX))
+        .unwrap_or(false)
+}
+
+// interface names may contain characters that are awkward in file names
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use dns_lookup::lookup_addr;
+
+lazy_static! {
+    static ref PTR_CACHE: Mutex<PtrCache> = Default::default();
+    static ref LOOKUPS: Sender<IpAddr> = spawn_workers();
+}
+
+/**
+ * This module resolves addresses to names with PTR lookups on a small
+ * pool of threads, so a slow resolver can't hold up a websocket client,
+ * and remembers both the names found and the addresses without one
+ */
+
+const WORKERS: usize = 4;
+// lookups waiting for a worker, more are answered as unresolved
+const MAX_QUEUED: usize = 256;
+// how long a client waits, the lookup carries on and is cached when done
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const RESOLVED_TTL: Duration = Duration::from_secs(60 * 60);
+const UNRESOLVED_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_CACHED: usize = 10000;
+
+enum PtrEntry {
+    // clients waiting for a queued or running lookup
+    Pending(Vec<Sender<Option<String>>>),
+    Done {
+        hostname: Option<String>,
+        expires: Instant,
+    },
+}
+
+#[derive(Default)]
+struct PtrCache {
+    entries: HashMap<IpAddr, PtrEntry>,
+}
+
+impl PtrCache {
+    // drops expired answers, or the one expiring soonest
+    fn make_room(&mut self) {
+        if self.entries.len() < MAX_CACHED {
+            return;
+        }
+
+        let now = Instant::now();
+        self.entries.retain(|_, entry| match entry {
+            PtrEntry::Done { expires, .. } => *expires > now,
+            PtrEntry::Pending(_) => true,
+        });
+        if self.entries.len() < MAX_CACHED {
+            return;
+        }
+
+        let soonest = self
+            .entries
+            .iter()
+            .filter_map(|(ip, entry)| match entry {
+                PtrEntry::Done { expires, .. } => Some((*expires, *ip)),
+                PtrEntry::Pending(_) => None,
+            })
+            .min()
+            .map(|(_, ip)| ip);
+        if let Some(ip) = soonest {
+            self.entries.remove(&ip);
+        }
+    }
+}
+
+fn spawn_workers() -> Sender<IpAddr> {
+    let (tx, rx) = bounded(MAX_QUEUED);
+
+    for id in 0..WORKERS {
+        let rx: Receiver<IpAddr> = rx.clone();
+        thread::Builder::new()
+            .name(format!("reverse-dns-{}", id))
+            .spawn(move || {
+                for ip in rx.iter() {
+                    let hostname = sys_lookup(&ip);
+                    finish(ip, hostname);
+                }
+            })
+            .unwrap();
+    }
+
+    tx
+}
+
+fn sys_lookup(ip: &IpAddr) -> Option<String> {
+    match lookup_addr(ip) {
+        // without a PTR record the address comes back as text
+        Ok(hostname) if hostname == ip.to_string() => None,
+        Ok(hostname) => Some(hostname),
+        Err(e) => {
+            println!("Reverse lookup of {} failed: {}", ip, e);
+            None
+        }
+    }
+}
+
+// caches the answer and hands it to the clients waiting for it
+fn finish(ip: IpAddr, hostname: Option<String>) {
+    let ttl = if hostname.is_some() {
+        RESOLVED_TTL
+    } else {
+        UNRESOLVED_TTL
+    };
+
+    let entry = PtrEntry::Done {
+        hostname: hostname.clone(),
+        expires: Instant::now() + ttl,
+    };
+    let previous = PTR_CACHE.lock().unwrap().entries.insert(ip, entry);
+
+    if let Some(PtrEntry::Pending(waiters)) = previous {
+        for waiter in waiters {
+            // the client may have stopped waiting
+            let _ = waiter.send(hostname.clone());
+        }
+    }
+}
+
+/// The PTR name of an address, None if it has none or took too long
+pub fn resolve(ip: IpAddr) -> Option<String> {
+    let (reply, answer) = bounded(1);
+
+    {
+        let mut cache = PTR_CACHE.lock().unwrap();
+        match cache.entries.get_mut(&ip) {
+            Some(PtrEntry::Done { hostname, expires }) if *expires > Instant::now() => {
+                return hostname.clone();
+            }
+            Some(PtrEntry::Pending(waiters)) => waiters.push(reply),
+            _ => {
+                if LOOKUPS.try_send(ip).is_err() {
+                    println!("Reverse lookup queue full, skipping {}", ip);
+                    return None;
+                }
+                cache.make_room();
+                cache.entries.insert(ip, PtrEntry::Pending(vec![reply]));
+            }
+        }
+    }
+
+    match answer.recv_timeout(LOOKUP_TIMEOUT) {
+        Ok(hostname) => hostname,
+        Err(_) => {
+            println!("Reverse lookup of {} timed out", ip);
+            None
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+/**
+ * Messages exchanged with websocket clients
+ */
+
+// packet event, t is "t" for tcp and "u" for udp
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PacketInfo {
+    pub len: u16,
+    pub dest: String,
+    pub src: String,
+    pub dest_port: u16,
+    pub src_port: u16,
+    pub t: String,
+    // capture interface the packet was seen on
+    pub interface: String,
+    // vlan ids, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vlans: Vec<u16>,
+    // mpls labels, top of stack first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mpls: Vec<u32>,
+    // tunnels the packet was carried in, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tunnels: Vec<TunnelInfo>,
+    // capture time since the unix epoch
+    pub ts: Duration,
+}
+
+// outer endpoints of a decapsulated tunnel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TunnelInfo {
+    // gre, ipip, vxlan or geneve
+    pub kind: String,
+    pub src: IpAddr,
+    pub dest: IpAddr,
+    // gre key or vxlan/geneve vni
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+}
+
+// eg. { req: "lookup", value: "1.1.1.1" }
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientRequest {
+    pub req: String,
+    pub value: String,
+}
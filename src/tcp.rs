@@ -1,36 +1,133 @@
-use std::cmp;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::RwLock;
 use tls_parser::{
-    parse_tls_encrypted, parse_tls_extensions, parse_tls_plaintext, TlsExtension, TlsMessage,
-    TlsMessageHandshake, TlsVersion,
+    parse_tls_extensions, parse_tls_plaintext, parse_tls_record_with_header, TlsExtension,
+    TlsMessage, TlsMessageHandshake, TlsRecordHeader, TlsRecordType, TlsVersion,
 };
 
-use itertools::Itertools;
+use std::time::Duration;
 
-use std::time::{Duration, Instant};
+use serde::Serialize;
 
-use md5;
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
 
-use tls_parser::tls::*;
-use tls_parser::tls_extensions::*;
+use super::config::CONFIG;
+use super::fingerprint::{
+    build_ja3_fingerprint, build_ja3s_fingerprint, build_ja4_fingerprint, build_ja4s_fingerprint,
+    ja3_hash,
+};
+use super::flows::FlowKey;
+use super::tcp_stream::{Parsed, StreamContext};
+use super::tls::TlsHandshake;
+use super::tls_alert::{record_alert, TlsAlert};
+use super::tls_anomaly::{check_server_hello, send_anomaly, TlsAnomaly, TlsAnomalyReport};
+use super::tls_stats;
+use super::x509::parse_certificate;
 
 lazy_static! {
     pub static ref TCP_STATS: RwLock<TcpStats> = Default::default();
 }
 
-#[derive(Debug, Copy, Clone)]
+const TLS_RECORD_HEADER_LEN: usize = 5;
+// 2^14 plus the most encryption may add
+const MAX_TLS_RECORD_LEN: usize = 16384 + 2048;
+const TLS_CHANGE_CIPHER_SPEC: u8 = 20;
+const TLS_HANDSHAKE: u8 = 22;
+const TLS_APPLICATION_DATA: u8 = 23;
+const TLS_HEARTBEAT: u8 = 24;
+// handshake messages have a type and a 24 bit length
+const HANDSHAKE_HEADER_LEN: usize = 4;
+// enough for long certificate chains spread over several records
+const MAX_HANDSHAKE_BUFFER: usize = 64 * 1024;
+// a misbehaving peer can keep sending warnings
+const MAX_CONNECTION_ALERTS: usize = 16;
+
+// times are packet capture timestamps since the unix epoch
+#[derive(Debug, Clone)]
 pub struct ConnStat {
     client_tls_version: u16,
-    client_time: Instant,
-    server_tls_version: u16,
-    server_time: Instant,
+    client_time: Duration,
     time_to_application_data: Duration,
+    // tls 1.3 application data records from the client after ServerHello
+    client_encrypted_records: u32,
+    handshake: TlsHandshake,
+    // the last handshake message seen from either side
+    stage: &'static str,
+    from_lower: HandshakeBuffer,
+    from_upper: HandshakeBuffer,
+}
+
+impl ConnStat {
+    // client and server from the ClientHello, or a guess that the lower port is the server
+    fn endpoints(&self, key: &FlowKey) -> ((IpAddr, u16), (IpAddr, u16)) {
+        match (self.handshake.client, self.handshake.client_port) {
+            (Some(ip), Some(port)) => ((ip, port), key.peer((ip, port))),
+            _ if key.lower.1 < key.upper.1 => (key.upper, key.lower),
+            _ => (key.lower, key.upper),
+        }
+    }
+
+    fn is_encrypted(&self, key: &FlowKey, source: (IpAddr, u16)) -> bool {
+        if source == key.lower {
+            self.from_lower.encrypted
+        } else {
+            self.from_upper.encrypted
+        }
+    }
+
+    fn handshake_buffer(&mut self, key: &FlowKey, source: (IpAddr, u16)) -> &mut HandshakeBuffer {
+        if source == key.lower {
+            &mut self.from_lower
+        } else {
+            &mut self.from_upper
+        }
+    }
+}
+
+// a handshake message can span records, or a record hold several messages
+#[derive(Debug, Clone, Default)]
+struct HandshakeBuffer {
+    buffer: Vec<u8>,
+    // after a tls 1.2 ChangeCipherSpec the handshake records can't be read
+    encrypted: bool,
+    encrypted_records: u32,
+}
+
+impl HandshakeBuffer {
+    // takes the complete messages off the front of the buffer
+    fn complete_messages(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut offset = 0;
+
+        while offset + HANDSHAKE_HEADER_LEN <= self.buffer.len() {
+            let header = &self.buffer[offset..offset + HANDSHAKE_HEADER_LEN];
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let end = offset + HANDSHAKE_HEADER_LEN + len;
+            if end > self.buffer.len() {
+                break;
+            }
+
+            messages.push(self.buffer[offset..end].to_vec());
+            offset = end;
+        }
+
+        self.buffer.drain(..offset);
+
+        if self.buffer.len() > MAX_HANDSHAKE_BUFFER {
+            // never going to complete, stop collecting
+            self.buffer = Vec::new();
+            self.encrypted = true;
+        }
+
+        messages
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TcpStats {
-    conn_map: HashMap<String, ConnStat>,
+    conn_map: HashMap<FlowKey, ConnStat>,
 }
 
 impl Default for TcpStats {
@@ -46,90 +143,167 @@ impl TcpStats {
         }
     }
 
-    pub fn get_or_create_conn(&mut self, key: String) -> Option<&mut ConnStat> {
-        if !self.conn_map.contains_key(&key) {
+    pub fn get_or_create_conn(&mut self, key: &FlowKey) -> Option<&mut ConnStat> {
+        if !self.conn_map.contains_key(key) {
             let stat = ConnStat {
                 client_tls_version: 0,
-                server_tls_version: 0,
-                client_time: Instant::now(),
-                server_time: Instant::now(),
+                client_time: Duration::new(0, 0),
                 time_to_application_data: Duration::new(0, 0),
+                client_encrypted_records: 0,
+                handshake: Default::default(),
+                stage: "none",
+                from_lower: Default::default(),
+                from_upper: Default::default(),
             };
 
             self.conn_map.insert(key.clone(), stat);
         }
 
-        self.conn_map.get_mut(&key)
+        self.conn_map.get_mut(key)
     }
 
-    pub fn count(&mut self) {
-        let map = self.conn_map.clone();
-        let len = map.keys().len();
-
-        let mut client_12_count = 0;
-        let mut client_13_count = 0;
-        let mut server_12_count = 0;
-        let mut server_13_count = 0;
-        let mut total_12_duration = Duration::new(0, 0);
-        let mut total_13_duration = Duration::new(0, 0);
-
-        for stat in map.values() {
-            if stat.client_tls_version == TlsVersion::Tls12.0 {
-                client_12_count += 1;
-            } else if stat.client_tls_version == TlsVersion::Tls13.0 {
-                client_13_count += 1;
+    pub fn get_conn(&self, key: &FlowKey) -> Option<&ConnStat> {
+        self.conn_map.get(key)
+    }
+
+    pub fn remove_conn(&mut self, key: &FlowKey) {
+        self.conn_map.remove(key);
+    }
+}
+
+/// Frames tls records out of a reassembled stream, complete handshake
+/// records are parsed and encrypted application data is skipped
+pub fn parse_tls_stream(stream: &[u8], context: &StreamContext) -> Parsed {
+    let mut offset = 0;
+
+    while offset + TLS_RECORD_HEADER_LEN <= stream.len() {
+        let header = &stream[offset..offset + TLS_RECORD_HEADER_LEN];
+        let content_type = header[0];
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+        // not tls, or we've lost the record boundaries
+        let valid = content_type >= TLS_CHANGE_CIPHER_SPEC
+            && content_type <= TLS_HEARTBEAT
+            && header[1] == 3
+            && record_len <= MAX_TLS_RECORD_LEN;
+        if !valid {
+            return Parsed::Done;
+        }
+
+        let record_end = offset + TLS_RECORD_HEADER_LEN + record_len;
+
+        if content_type == TLS_APPLICATION_DATA {
+            application_data(context);
+            // may run past what we have, the rest is skipped as it arrives
+            offset = record_end;
+            continue;
+        }
+
+        if record_end > stream.len() {
+            break;
+        }
+
+        let record = &stream[offset..record_end];
+        match content_type {
+            TLS_HANDSHAKE => handshake_record(&record[TLS_RECORD_HEADER_LEN..], context),
+            TLS_CHANGE_CIPHER_SPEC => change_cipher_spec(context),
+            // alerts and heartbeats can only be read before ChangeCipherSpec
+            _ if !encrypted(context) => parse_tls_record(record, context),
+            _ => {}
+        }
+        offset = record_end;
+    }
+
+    Parsed::Consumed(offset)
+}
+
+fn application_data(context: &StreamContext) {
+    let mut tcp_stats = TCP_STATS.write().unwrap();
+    let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+    // only timed once, and when we saw both hellos
+    if conn.time_to_application_data != Duration::new(0, 0)
+        || conn.handshake.client_hello_ts.is_none()
+        || conn.handshake.server_hello_ts.is_none()
+    {
+        return;
+    }
+
+    // tls 1.3 sends the rest of the handshake as application data records,
+    // the server's flight and the client's Finished come before any data
+    if conn.handshake.version_id == TlsVersion::Tls13.0 {
+        let (client, _) = conn.endpoints(context.key);
+        if context.source != client {
+            return;
+        }
+
+        conn.client_encrypted_records += 1;
+        if conn.client_encrypted_records < 2 {
+            return;
+        }
+    }
+
+    conn.time_to_application_data = elapsed(conn.client_time, context.ts);
+    tls_stats::application_data(&conn.handshake, conn.time_to_application_data);
+}
+
+// handshake records are collected per direction and split into messages
+fn handshake_record(fragment: &[u8], context: &StreamContext) {
+    let messages = {
+        let mut tcp_stats = TCP_STATS.write().unwrap();
+        let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+        let buffer = conn.handshake_buffer(context.key, context.source);
+        if buffer.encrypted {
+            buffer.encrypted_records += 1;
+            // the first is Finished, any more handshake is a renegotiation
+            if buffer.encrypted_records == 2 {
+                let report = renegotiation(conn, context);
+                report_anomalies(conn, context, vec![report]);
             }
+            return;
+        }
 
-            if stat.server_tls_version == TlsVersion::Tls12.0 {
-                server_12_count += 1;
-                let lapsed = stat.server_time.duration_since(stat.client_time);
-                // total_12_duration += lapsed;
-                total_12_duration += stat.time_to_application_data;
-            } else if stat.server_tls_version == TlsVersion::Tls13.0 {
-                server_13_count += 1;
-                let lapsed = stat.server_time.duration_since(stat.client_time);
-                // total_13_duration += lapsed;
-                total_13_duration += stat.time_to_application_data;
+        buffer.buffer.extend_from_slice(fragment);
+        buffer.complete_messages()
+    };
+
+    // parsed as a record of their own, the length is only checked for heartbeats
+    let header = TlsRecordHeader {
+        record_type: TlsRecordType::Handshake,
+        version: TlsVersion::Tls12,
+        len: 0,
+    };
+    for message in messages {
+        if let Ok((_, msgs)) = parse_tls_record_with_header(&message, &header) {
+            for msg in msgs {
+                handle_message(msg, context);
             }
         }
+    }
+}
 
-        if len % 1 == 0 {
-            println!("TLS Total: {}", len);
-            println!("Client TLS 1.2: {}", client_12_count);
-            println!("Client TLS 1.3: {}", client_13_count);
-            println!("server TLS 1.2: {}", server_12_count);
-            println!("server TLS 1.3: {}", server_13_count);
+fn change_cipher_spec(context: &StreamContext) {
+    let mut tcp_stats = TCP_STATS.write().unwrap();
+    let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
 
-            if server_12_count > 0 {
-                println!("Avg RTT 1.2: {:?}", total_12_duration / server_12_count)
-            };
-            if server_13_count > 0 {
-                println!("Avg RTT 1.3: {:?}", total_13_duration / server_13_count)
-            };
-        }
+    conn.stage = "change_cipher_spec";
+
+    // tls 1.3 sends it for middlebox compatibility, it can come before
+    // a second ClientHello
+    if conn.handshake.version_id != TlsVersion::Tls13.0 {
+        let buffer = conn.handshake_buffer(context.key, context.source);
+        buffer.buffer = Vec::new();
+        buffer.encrypted = true;
     }
 }
 
-pub fn parse_tcp_payload(packet: &[u8], key: &str) {
-    // if packet.len() > 4 {
-    //     if packet[0] == 0x17 {
-    //         return;
-    //     }
-    //     println!(
-    //         "packet {:x} {:x} {:x} {:x}",
-    //         packet[0], packet[1], packet[2], packet[3]
-    //     );
-    //     // 17 3 3 0 Application Data, TLS 1.2
-    // }
-
-    // TODO skip to the end of TCP header
-
-    // if let Ok(encrypted) = parse_tls_encrypted(&packet) {
-    //     let (_, msg) = encrypted;
-    //     match (msg.hdr.record_type) {
-    //     }
-    // }
+fn encrypted(context: &StreamContext) -> bool {
+    match TCP_STATS.read().unwrap().get_conn(context.key) {
+        Some(conn) => conn.is_encrypted(context.key, context.source),
+        None => false,
+    }
+}
 
+fn parse_tls_record(packet: &[u8], context: &StreamContext) {
     let r = parse_tls_plaintext(&packet);
     match r {
         Ok(v) => {
@@ -137,217 +311,286 @@ pub fn parse_tcp_payload(packet: &[u8], key: &str) {
             let (_, plain_text) = v;
             for m in plain_text.msg {
                 // println!("msg {:?}", m);
-                // Handshake(ClientKeyExchange)
-                // Alert(TlsMessageAlert
-                // Handshake(ClientHello(TlsClientHelloContents
-
-                match m {
-                    TlsMessage::Handshake(TlsMessageHandshake::ClientHello(client_hello)) => {
-                        // println!("Client Hello client_hello {:?}", client_hello);
-                        // ciphers
-                        // println!("Client Hello Version {}", client_hello.version);
-
-                        let mut highest = client_hello.version.0;
-
-                        if let Some(v) = client_hello.ext {
-                            if let Ok((_, ref extensions)) = parse_tls_extensions(v) {
-                                // println!("Client Hello Extensions {:?}", extensions);
-                                // TlsExtension::EllipticCurves
-                                // TlsExtension::ALPN
-                                // TlsExtension::SignatureAlgorithms
-                                // TlsExtension::KeyShare
-                                // TlsExtension::PreSharedKey
-
-                                for ext in extensions {
-                                    match ext {
-                                        TlsExtension::SNI(sni) => {
-                                            for (_, b) in sni {
-                                                println!(
-                                                    "Sni: {}",
-                                                    std::str::from_utf8(b).unwrap_or("")
-                                                );
-                                            }
-                                        }
-                                        TlsExtension::SupportedVersions(sv) => {
-                                            highest = highest_version(highest, sv);
-                                        }
-                                        _ => {}
-                                    }
-                                }
-
-                                let ja3 = build_ja3_fingerprint(&client_hello, &extensions);
-                                let digest = md5::compute(&ja3);
-                                println!("JA3: {} --> {:x}", ja3, digest);
-                            }
-                        }
-
-                        // get connection
-                        let mut tcp_stats = TCP_STATS.write().unwrap();
-                        let conn = tcp_stats.get_or_create_conn(key.to_owned()).unwrap();
-                        conn.client_tls_version = highest;
-                        conn.client_time = Instant::now();
+                handle_message(m, context);
+            }
+        }
+        _ => {
+            // println!("Not TLS {:?}", e)
+        }
+    }
+}
 
-                        println!("Client Hello Version {} - {}", key, TlsVersion(highest));
+fn handle_message(m: TlsMessage, context: &StreamContext) {
+    if let TlsMessage::Handshake(msg) = &m {
+        let mut tcp_stats = TCP_STATS.write().unwrap();
+        let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+        conn.stage = handshake_stage(msg);
+    }
 
-                        tcp_stats.count();
-                    }
-                    TlsMessage::Handshake(TlsMessageHandshake::ServerHello(server_hello)) => {
-                        // println!("Server Hello server_hello {:?}", server_hello);
-
-                        let mut highest = server_hello.version.0;
-                        if let Some(v) = server_hello.ext {
-                            if let Ok((_, ref extensions)) = parse_tls_extensions(v) {
-                                // println!("Server Hello Extensions {:?}", extensions);
-                                // TlsExtension::KeyShare count
-                                // Hello Retry stats
-                                // CipherSuit, ALPN
-                                // OCSP, Key Exchange
-
-                                for ext in extensions {
-                                    match ext {
-                                        TlsExtension::SupportedVersions(sv) => {
-                                            highest = highest_version(highest, sv);
-                                            if highest < TlsVersion::Tls13.0 {
-                                                println!(
-                                                    "************** DOWNGRADE ************???"
-                                                );
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                        }
+    match m {
+        TlsMessage::Handshake(TlsMessageHandshake::ClientHello(client_hello)) => {
+            let extensions = hello_extensions(client_hello.ext);
+
+            // get connection
+            let mut tcp_stats = TCP_STATS.write().unwrap();
+            let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+            // a second ClientHello after a HelloRetryRequest isn't a new handshake
+            let first_hello = conn.handshake.client_hello_ts.is_none();
+            let renegotiating =
+                conn.handshake.server_hello_ts.is_some() && !conn.handshake.hello_retry;
+            conn.handshake.client_hello(
+                &client_hello,
+                &extensions,
+                context.source,
+                context.key.peer(context.source),
+                context.ts,
+            );
+            conn.client_tls_version = conn.handshake.client_version_id;
+            conn.client_time = context.ts;
+
+            let raw_extensions = client_hello.ext.unwrap_or(&[]);
+            let ja3 = build_ja3_fingerprint(&client_hello, &extensions);
+            conn.handshake.ja3 = Some(ja3_hash(&ja3));
+            conn.handshake.ja4 = Some(build_ja4_fingerprint(
+                &client_hello,
+                &extensions,
+                raw_extensions,
+            ));
+
+            if CONFIG.debug {
+                println!(
+                    "Client Hello Version {:?} - {}",
+                    context.key,
+                    TlsVersion(conn.client_tls_version)
+                );
+            }
 
-                        // get connection
-                        let mut tcp_stats = TCP_STATS.write().unwrap();
-                        let conn = tcp_stats.get_or_create_conn(key.to_owned()).unwrap();
-                        conn.server_tls_version = highest;
-                        conn.server_time = Instant::now();
-                        println!(
-                            "Server Hello Supported Version {} - {}",
-                            key,
-                            TlsVersion(highest)
-                        );
-                        println!("{:?}", conn.server_time.duration_since(conn.client_time));
-
-                        tcp_stats.count();
-                    }
-                    TlsMessage::ChangeCipherSpec => {
-                        // For TLS 1.2, usually marks encrypted messages after.
-                    }
-                    TlsMessage::Handshake(msg) => {
-                        // println!("Handshake msg {:?}", msg);
-                    }
+            if first_hello {
+                tls_stats::client_hello(&conn.handshake);
+            }
+            if renegotiating {
+                let report = renegotiation(conn, context);
+                report_anomalies(conn, context, vec![report]);
+            }
+            send_handshake(context.tx, "client_hello", &conn.handshake);
+            send_fingerprint(context.tx, "client", &conn.handshake);
+        }
+        TlsMessage::Handshake(TlsMessageHandshake::ServerHello(server_hello)) => {
+            let extensions = hello_extensions(server_hello.ext);
+
+            // get connection
+            let mut tcp_stats = TCP_STATS.write().unwrap();
+            let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+
+            if conn
+                .handshake
+                .server_hello(&server_hello, &extensions, context.ts)
+            {
+                // the client tries again with another key share
+                send_handshake(context.tx, "hello_retry", &conn.handshake);
+                return;
+            }
 
-                    _ => {}
-                }
+            let reports = check_server_hello(&conn.handshake, &server_hello, context.ts);
+            report_anomalies(conn, context, reports);
+
+            let raw_extensions = server_hello.ext.unwrap_or(&[]);
+            let ja3s = build_ja3s_fingerprint(&server_hello, raw_extensions);
+            conn.handshake.ja3s = Some(ja3_hash(&ja3s));
+            conn.handshake.ja4s = Some(build_ja4s_fingerprint(
+                &server_hello,
+                &extensions,
+                raw_extensions,
+            ));
+            if CONFIG.debug {
+                println!(
+                    "Server Hello Supported Version {:?} - {}",
+                    context.key,
+                    TlsVersion(conn.handshake.version_id)
+                );
             }
 
-            match (plain_text.hdr.record_type) {
-                ApplicationData => {
-                    // println!("Application Data {:?}", app_data);
-                    let mut tcp_stats = TCP_STATS.write().unwrap();
-                    let conn = tcp_stats.get_or_create_conn(key.to_owned()).unwrap();
-                    if conn.time_to_application_data == Duration::new(0, 0)
-                        // TODO filter that we cannot fetch tcp stat, right now just a heuristic
-                        && Instant::now().duration_since(conn.client_time) > Duration::from_millis(1)
-                    {
-                        println!("First Application Data");
-                        conn.time_to_application_data =
-                            Instant::now().duration_since(conn.client_time);
-                        tcp_stats.count();
+            tls_stats::server_hello(&conn.handshake);
+            send_handshake(context.tx, "server_hello", &conn.handshake);
+            send_fingerprint(context.tx, "server", &conn.handshake);
+        }
+        TlsMessage::Handshake(TlsMessageHandshake::Certificate(certificate)) => {
+            let mut tcp_stats = TCP_STATS.write().unwrap();
+            let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
+            let now = context.ts.as_secs() as i64;
+
+            let mut certificates = Vec::new();
+            for (i, cert) in certificate.cert_chain.iter().enumerate() {
+                let mut info = match parse_certificate(cert.data) {
+                    Some(info) => info,
+                    None => {
+                        if CONFIG.debug {
+                            println!("Unparsable certificate {:?}", context.key);
+                        }
+                        continue;
+                    }
+                };
+
+                info.check_validity(now);
+                // only the leaf is for the server name
+                if i == 0 {
+                    if let Some(sni) = &conn.handshake.sni {
+                        info.check_hostname(sni);
                     }
                 }
-                _ => {
-                    // println!("Something {:?}", msg);
-                }
+                certificates.push(info);
             }
+
+            conn.handshake.certificates = certificates;
+            send_handshake(context.tx, "certificate", &conn.handshake);
         }
-        _ => {
-            // println!("Not TLS {:?}", e)
-        }
-    }
-}
+        TlsMessage::Alert(alert) => {
+            let mut tcp_stats = TCP_STATS.write().unwrap();
+            let conn = tcp_stats.get_or_create_conn(context.key).unwrap();
 
-fn highest_version(highest: u16, versions: &Vec<TlsVersion>) -> u16 {
-    let mut highest = highest;
+            let (client, server) = conn.endpoints(context.key);
+            let side = if context.source == client {
+                "client"
+            } else {
+                "server"
+            };
+            let alert = TlsAlert::new(alert.severity.0, alert.code.0, side, conn.stage, context.ts);
 
-    for version in versions {
-        if !(GREASE_TABLE.iter().any(|g| g == &version.0)) {
-            highest = cmp::max(highest, version.0);
+            if conn.handshake.alerts.len() < MAX_CONNECTION_ALERTS {
+                conn.handshake.alerts.push(alert.clone());
+            }
+            record_alert(client, server, &conn.handshake.sni, &alert, context.tx);
         }
+        TlsMessage::Handshake(_) => {}
+
+        _ => {}
     }
+}
 
-    highest
+fn renegotiation(conn: &ConnStat, context: &StreamContext) -> TlsAnomalyReport {
+    let (client, _) = conn.endpoints(context.key);
+    let side = if context.source == client {
+        "client"
+    } else {
+        "server"
+    };
+    let secure = if conn.handshake.secure_renegotiation {
+        "with"
+    } else {
+        "without"
+    };
+
+    TlsAnomalyReport::new(
+        TlsAnomaly::Renegotiation,
+        format!("started by the {}, {} renegotiation_info", side, secure),
+        context.ts,
+    )
 }
 
-// from https://github.com/rusticata/rusticata/blob/master/src/tls.rs
-
-/// https://tools.ietf.org/html/draft-davidben-tls-grease-00
-const GREASE_TABLE: &[u16] = &[
-    0x0a0a, 0x1a1a, 0x2a2a, 0x3a3a, 0x4a4a, 0x5a5a, 0x6a6a, 0x7a7a, 0x8a8a, 0x9a9a, 0xaaaa, 0xbaba,
-    0xcaca, 0xdada, 0xeaea, 0xfafa,
-];
-
-/// SSLVersion,Cipher,SSLExtension,EllipticCurve,EllipticCurvePointFormat
-pub fn build_ja3_fingerprint(
-    content: &TlsClientHelloContents,
-    extensions: &Vec<TlsExtension>,
-) -> String {
-    let mut ja3 = format!("{},", u16::from(content.version));
-
-    let ciphers = content.ciphers.iter().join("-");
-    ja3.push_str(&ciphers);
-    ja3.push(',');
-
-    let ext_str = extensions
-        .iter()
-        .map(|x| TlsExtensionType::from(x))
-        .map(|x| u16::from(x))
-        .filter(|x| !(GREASE_TABLE.iter().any(|g| g == x)))
-        .join("-");
-    ja3.push_str(&ext_str);
-    ja3.push(',');
-
-    for ext in extensions {
-        match ext {
-            &TlsExtension::EllipticCurves(ref ec) => {
-                ja3.push_str(
-                    &ec.iter()
-                        .map(|x| x.0)
-                        .filter(|x| !(GREASE_TABLE.iter().any(|g| g == x)))
-                        .join("-"),
-                );
-            }
-            _ => (),
-        }
+fn report_anomalies(conn: &mut ConnStat, context: &StreamContext, reports: Vec<TlsAnomalyReport>) {
+    let (client, server) = conn.endpoints(context.key);
+
+    for report in reports {
+        send_anomaly(context.tx, client, server, &conn.handshake.sni, &report);
+        conn.handshake.anomalies.push(report);
     }
-    ja3.push(',');
+}
 
-    for ext in extensions {
-        match ext {
-            &TlsExtension::EcPointFormats(ref pf) => {
-                ja3.push_str(&pf.iter().join("-"));
-            }
-            _ => (),
-        }
+fn handshake_stage(msg: &TlsMessageHandshake) -> &'static str {
+    match msg {
+        TlsMessageHandshake::HelloRequest => "hello_request",
+        TlsMessageHandshake::ClientHello(_) => "client_hello",
+        TlsMessageHandshake::ServerHello(_) => "server_hello",
+        TlsMessageHandshake::NewSessionTicket(_) => "new_session_ticket",
+        TlsMessageHandshake::Certificate(_) => "certificate",
+        TlsMessageHandshake::ServerKeyExchange(_) => "server_key_exchange",
+        TlsMessageHandshake::CertificateRequest(_) => "certificate_request",
+        TlsMessageHandshake::ServerDone(_) => "server_hello_done",
+        TlsMessageHandshake::CertificateVerify(_) => "certificate_verify",
+        TlsMessageHandshake::ClientKeyExchange(_) => "client_key_exchange",
+        TlsMessageHandshake::Finished(_) => "finished",
+        TlsMessageHandshake::CertificateStatus(_) => "certificate_status",
+        _ => "handshake",
     }
+}
 
-    ja3
+fn hello_extensions(ext: Option<&[u8]>) -> Vec<TlsExtension> {
+    ext.and_then(|ext| parse_tls_extensions(ext).ok())
+        .map(|(_, extensions)| extensions)
+        .unwrap_or_default()
 }
 
-fn is_tls13(_content: &TlsServerHelloContents, extensions: &Vec<TlsExtension>) -> bool {
-    // look extensions, find the TlsSupportedVersion
-    extensions
-        .iter()
-        .find(|&ext| TlsExtensionType::SupportedVersions == ext.into())
-        .map(|ref ext| {
-            if let TlsExtension::SupportedVersions(ref versions) = ext {
-                versions.len() == 1 && versions[0] == TlsVersion::Tls13
-            } else {
-                false
-            }
-        })
-        .unwrap_or(false)
+#[derive(Serialize)]
+struct TlsHandshakeEvent<'a> {
+    r#type: &'static str,
+    // client_hello, hello_retry, server_hello or certificate
+    stage: &'static str,
+    #[serde(flatten)]
+    handshake: &'a TlsHandshake,
+}
+
+fn send_handshake(tx: &Sender<OwnedMessage>, stage: &'static str, handshake: &TlsHandshake) {
+    let event = TlsHandshakeEvent {
+        r#type: "tls_handshake",
+        stage,
+        handshake,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+#[derive(Serialize)]
+struct TlsFingerprintEvent<'a> {
+    r#type: &'static str,
+    // client fingerprints come from the ClientHello, server ones from the ServerHello
+    side: &'static str,
+    client: Option<IpAddr>,
+    server: Option<IpAddr>,
+    server_port: Option<u16>,
+    sni: &'a Option<String>,
+    ja3: &'a Option<String>,
+    ja4: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ja3s: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ja4s: &'a Option<String>,
+}
+
+fn send_fingerprint(tx: &Sender<OwnedMessage>, side: &'static str, handshake: &TlsHandshake) {
+    let event = TlsFingerprintEvent {
+        r#type: "tls_fingerprint",
+        side,
+        client: handshake.client,
+        server: handshake.server,
+        server_port: handshake.server_port,
+        sni: &handshake.sni,
+        ja3: &handshake.ja3,
+        ja4: &handshake.ja4,
+        ja3s: &handshake.ja3s,
+        ja4s: &handshake.ja4s,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+pub fn current_handshakes() -> Vec<TlsHandshake> {
+    TCP_STATS
+        .read()
+        .unwrap()
+        .conn_map
+        .values()
+        .map(|conn| conn.handshake.clone())
+        .collect()
+}
+
+/// Drops connection state once its flow has ended
+pub fn forget_conn(key: &FlowKey) {
+    TCP_STATS.write().unwrap().remove_conn(key);
+}
+
+// capture timestamps can go backwards across interfaces
+fn elapsed(from: Duration, to: Duration) -> Duration {
+    to.checked_sub(from).unwrap_or_default()
 }
@@ -0,0 +1,278 @@
+use serde::Serialize;
+
+use std::time::Duration;
+
+/**
+ * This module looks for signs of trouble in one side of a tcp connection:
+ * retransmissions, out-of-order segments, duplicate acks, zero and full
+ * windows, and segments the capture never saw
+ */
+
+// segments filling a hole sooner than this after the last one are reordered, not resent
+const OUT_OF_ORDER_THRESHOLD: Duration = Duration::from_millis(3);
+// holes remembered per direction, the oldest are forgotten beyond this
+const MAX_GAPS: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TcpHealth {
+    pub retransmissions: u64,
+    // resent data the receiver had already acknowledged
+    pub spurious_retransmissions: u64,
+    pub out_of_order: u64,
+    pub dup_acks: u64,
+    pub zero_windows: u64,
+    pub window_full: u64,
+    // holes in the sequence space, segments lost before the capture point
+    pub lost_segments: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anomaly {
+    Retransmission,
+    SpuriousRetransmission,
+    OutOfOrder,
+    DupAck,
+    ZeroWindow,
+    WindowFull,
+    LostSegment,
+}
+
+impl Anomaly {
+    pub fn name(self) -> &'static str {
+        match self {
+            Anomaly::Retransmission => "retransmission",
+            Anomaly::SpuriousRetransmission => "spurious_retransmission",
+            Anomaly::OutOfOrder => "out_of_order",
+            Anomaly::DupAck => "dup_ack",
+            Anomaly::ZeroWindow => "zero_window",
+            Anomaly::WindowFull => "window_full",
+            Anomaly::LostSegment => "lost_segment",
+        }
+    }
+}
+
+/// The header fields of a segment the analysis needs
+pub struct Segment {
+    pub seq: u32,
+    pub ack: Option<u32>,
+    pub window: u16,
+    pub window_scale: Option<u8>,
+    pub payload_len: usize,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub ts: Duration,
+}
+
+/// What one side of the connection has sent so far
+#[derive(Debug, Clone, Default)]
+pub struct SenderState {
+    pub health: TcpHealth,
+    pub dup_acks: u32,
+    saw_syn: bool,
+    window_scale: Option<u8>,
+    // sequence number following the highest byte sent
+    next_seq: Option<u32>,
+    last_ack: Option<u32>,
+    last_raw_window: Option<u16>,
+    // scaled, unknown when the handshake was missed
+    last_window: Option<u32>,
+    last_segment_ts: Duration,
+    // missing sequence ranges, start inclusive end exclusive
+    gaps: Vec<(u32, u32)>,
+}
+
+impl SenderState {
+    /// Checks a segment from this side against what both sides have sent
+    pub fn analyze(&mut self, receiver: &SenderState, segment: &Segment) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+
+        if segment.rst {
+            return anomalies;
+        }
+
+        if segment.syn {
+            self.saw_syn = true;
+            self.window_scale = segment.window_scale;
+        }
+
+        let window = self.scaled_window(receiver, segment);
+
+        if !segment.syn && segment.window == 0 && self.last_raw_window != Some(0) {
+            anomalies.push(Anomaly::ZeroWindow);
+        }
+
+        // SYN and FIN take up a sequence number each
+        let seq_len = segment.payload_len as u32 + segment.syn as u32 + segment.fin as u32;
+        if seq_len > 0 {
+            let end = segment.seq.wrapping_add(seq_len);
+            self.check_sequence(receiver, segment, end, &mut anomalies);
+
+            // the segment reaches the right edge of the receiver's window
+            if segment.payload_len > 0 {
+                if let (Some(ack), Some(window)) = (receiver.last_ack, receiver.last_window) {
+                    if ack.wrapping_add(window) == end {
+                        anomalies.push(Anomaly::WindowFull);
+                    }
+                }
+            }
+
+            self.last_segment_ts = segment.ts;
+        }
+
+        if let Some(ack) = segment.ack {
+            let pure_ack = segment.payload_len == 0 && !segment.syn && !segment.fin;
+            let outstanding = receiver.next_seq.map_or(false, |next| seq_after(next, ack));
+
+            if pure_ack
+                && outstanding
+                && self.last_ack == Some(ack)
+                && self.last_raw_window == Some(segment.window)
+            {
+                self.dup_acks += 1;
+                anomalies.push(Anomaly::DupAck);
+            } else if self.last_ack != Some(ack) {
+                self.dup_acks = 0;
+            }
+
+            // acks can arrive reordered too, only move forward
+            if self.last_ack.map_or(true, |last| seq_after(ack, last)) {
+                self.last_ack = Some(ack);
+            }
+        }
+
+        self.last_raw_window = Some(segment.window);
+        self.last_window = window;
+
+        for anomaly in &anomalies {
+            self.count(*anomaly);
+        }
+
+        anomalies
+    }
+
+    fn check_sequence(
+        &mut self,
+        receiver: &SenderState,
+        segment: &Segment,
+        end: u32,
+        anomalies: &mut Vec<Anomaly>,
+    ) {
+        let next = match self.next_seq {
+            Some(next) => next,
+            None => {
+                self.next_seq = Some(end);
+                return;
+            }
+        };
+
+        if segment.seq == next {
+            self.next_seq = Some(end);
+            return;
+        }
+
+        if seq_after(segment.seq, next) {
+            anomalies.push(Anomaly::LostSegment);
+            self.add_gap(next, segment.seq);
+            self.next_seq = Some(end);
+            return;
+        }
+
+        // keep-alives resend the last byte, or nothing, one below next
+        let keep_alive = segment.payload_len <= 1
+            && !segment.syn
+            && !segment.fin
+            && segment.seq == next.wrapping_sub(1);
+        if keep_alive {
+            return;
+        }
+
+        let filled_gap = self.fill_gap(segment.seq, end);
+        let elapsed = segment
+            .ts
+            .checked_sub(self.last_segment_ts)
+            .unwrap_or_default();
+
+        if filled_gap && elapsed < OUT_OF_ORDER_THRESHOLD {
+            anomalies.push(Anomaly::OutOfOrder);
+        } else if receiver.last_ack.map_or(false, |ack| !seq_after(end, ack)) {
+            anomalies.push(Anomaly::SpuriousRetransmission);
+        } else {
+            anomalies.push(Anomaly::Retransmission);
+        }
+
+        // a resend can carry new data past what we'd seen
+        if seq_after(end, next) {
+            self.next_seq = Some(end);
+        }
+    }
+
+    // window scaling only applies once both SYNs were seen, and never to SYNs
+    fn scaled_window(&self, receiver: &SenderState, segment: &Segment) -> Option<u32> {
+        let window = segment.window as u32;
+
+        if segment.syn {
+            return Some(window);
+        }
+
+        if !self.saw_syn || !receiver.saw_syn {
+            return None;
+        }
+
+        match (self.window_scale, receiver.window_scale) {
+            (Some(shift), Some(_)) => Some(window << shift.min(14)),
+            _ => Some(window),
+        }
+    }
+
+    fn add_gap(&mut self, start: u32, end: u32) {
+        if self.gaps.len() >= MAX_GAPS {
+            self.gaps.remove(0);
+        }
+
+        self.gaps.push((start, end));
+    }
+
+    // removes the part of any gap the segment covers, true if it covered some
+    fn fill_gap(&mut self, start: u32, end: u32) -> bool {
+        let mut filled = false;
+        let mut remaining = Vec::with_capacity(self.gaps.len());
+
+        for &(gap_start, gap_end) in &self.gaps {
+            let overlaps = seq_after(end, gap_start) && seq_after(gap_end, start);
+            if !overlaps {
+                remaining.push((gap_start, gap_end));
+                continue;
+            }
+
+            filled = true;
+            if seq_after(start, gap_start) {
+                remaining.push((gap_start, start));
+            }
+            if seq_after(gap_end, end) {
+                remaining.push((end, gap_end));
+            }
+        }
+
+        self.gaps = remaining;
+        filled
+    }
+
+    fn count(&mut self, anomaly: Anomaly) {
+        let health = &mut self.health;
+        match anomaly {
+            Anomaly::Retransmission => health.retransmissions += 1,
+            Anomaly::SpuriousRetransmission => health.spurious_retransmissions += 1,
+            Anomaly::OutOfOrder => health.out_of_order += 1,
+            Anomaly::DupAck => health.dup_acks += 1,
+            Anomaly::ZeroWindow => health.zero_windows += 1,
+            Anomaly::WindowFull => health.window_full += 1,
+            Anomaly::LostSegment => health.lost_segments += 1,
+        }
+    }
+}
+
+// sequence numbers wrap, compare in serial number arithmetic
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
@@ -0,0 +1,541 @@
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
+use pnet::packet::Packet;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+use super::flows::{end_flow, FlowKey};
+use super::tcp_health::{Anomaly, Segment, SenderState, TcpHealth};
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<FlowKey, TcpConnection>> = Default::default();
+    // connections that closed or were reset, with the capture time they ended
+    static ref CLOSED: Mutex<HashMap<FlowKey, Duration>> = Default::default();
+}
+
+/**
+ * This module follows tcp connections through the three-way handshake
+ * and teardown, measuring handshake round trip times and publishing
+ * lifecycle events (opened, established, half_closed, reset, closed)
+ * and tcp_anomaly events from the per direction health checks
+ */
+
+// segments straggling in after a close are ignored for this long
+const CLOSE_LINGER: Duration = Duration::from_secs(60);
+const MAX_CLOSED: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    // one side has sent a FIN
+    HalfClosed,
+    Closed,
+    Reset,
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpConnection {
+    pub client: (IpAddr, u16),
+    pub server: (IpAddr, u16),
+    pub state: TcpState,
+    // picked up after the handshake, so there's no rtt
+    pub midstream: bool,
+    pub syn_ts: Option<Duration>,
+    pub syn_ack_ts: Option<Duration>,
+    pub established_ts: Option<Duration>,
+    pub client_isn: Option<u32>,
+    pub server_isn: Option<u32>,
+    // sequence number of each side's FIN
+    client_fin: Option<u32>,
+    server_fin: Option<u32>,
+    client_fin_acked: bool,
+    server_fin_acked: bool,
+    reset_by_client: bool,
+    // sequence analysis of what each side sent
+    client_side: SenderState,
+    server_side: SenderState,
+}
+
+impl TcpConnection {
+    fn new(client: (IpAddr, u16), server: (IpAddr, u16), state: TcpState) -> Self {
+        Self {
+            client,
+            server,
+            state,
+            midstream: state == TcpState::Established,
+            syn_ts: None,
+            syn_ack_ts: None,
+            established_ts: None,
+            client_isn: None,
+            server_isn: None,
+            client_fin: None,
+            server_fin: None,
+            client_fin_acked: false,
+            server_fin_acked: false,
+            reset_by_client: false,
+            client_side: Default::default(),
+            server_side: Default::default(),
+        }
+    }
+
+    /// Capture point to server and back, SYN to SYN-ACK
+    pub fn server_rtt(&self) -> Option<Duration> {
+        elapsed(self.syn_ts?, self.syn_ack_ts?)
+    }
+
+    /// Capture point to client and back, SYN-ACK to ACK
+    pub fn client_rtt(&self) -> Option<Duration> {
+        elapsed(self.syn_ack_ts?, self.established_ts?)
+    }
+
+    /// Whether a bare SYN starts a new connection on the same ports,
+    /// rather than being a retransmission of this one's
+    fn reused_by(&self, seq: u32) -> bool {
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => false,
+            TcpState::Established => true,
+            // RFC 1122 4.2.2.13, a new connection's ISN is past the old one's data
+            TcpState::HalfClosed | TcpState::Closed | TcpState::Reset => {
+                let isn = match self.client_isn {
+                    Some(isn) => isn,
+                    None => return true,
+                };
+                let end = self.client_fin.map_or(isn, |fin| fin.wrapping_add(1));
+                seq.wrapping_sub(isn) > end.wrapping_sub(isn)
+            }
+        }
+    }
+
+    /// Runs the health checks for a segment from one side
+    fn analyze(&mut self, from_client: bool, segment: &Segment) -> Vec<Anomaly> {
+        if from_client {
+            self.client_side.analyze(&self.server_side, segment)
+        } else {
+            self.server_side.analyze(&self.client_side, segment)
+        }
+    }
+
+    pub fn summary(&self, interface: &str) -> ConnectionSummary {
+        let server_rtt = self.server_rtt();
+        let client_rtt = self.client_rtt();
+        let handshake_rtt = match (server_rtt, client_rtt) {
+            (Some(server), Some(client)) => Some(millis(server + client)),
+            _ => None,
+        };
+
+        let reset_by = match (self.state, self.reset_by_client) {
+            (TcpState::Reset, true) => Some("client"),
+            (TcpState::Reset, false) => Some("server"),
+            _ => None,
+        };
+
+        ConnectionSummary {
+            interface: interface.to_owned(),
+            state: self.state,
+            client: self.client.0,
+            client_port: self.client.1,
+            server: self.server.0,
+            server_port: self.server.1,
+            midstream: self.midstream,
+            server_rtt: server_rtt.map(millis),
+            client_rtt: client_rtt.map(millis),
+            handshake_rtt,
+            reset_by,
+            client_health: self.client_side.health,
+            server_health: self.server_side.health,
+        }
+    }
+
+    /// Advances the state machine, returns the lifecycle events this caused
+    fn update(
+        &mut self,
+        from_client: bool,
+        flags: u8,
+        seq: u32,
+        ack: u32,
+        payload_len: usize,
+        ts: Duration,
+    ) -> Vec<&'static str> {
+        let mut events = Vec::new();
+
+        if self.state == TcpState::Closed || self.state == TcpState::Reset {
+            return events;
+        }
+
+        if flags & TcpFlags::RST != 0 {
+            self.state = TcpState::Reset;
+            self.reset_by_client = from_client;
+            events.push("reset");
+            return events;
+        }
+
+        let syn = flags & TcpFlags::SYN != 0;
+        let has_ack = flags & TcpFlags::ACK != 0;
+
+        match self.state {
+            TcpState::SynSent => {
+                if syn && !has_ack && from_client {
+                    // a retransmitted SYN, the SYN-ACK answers the latest one
+                    self.syn_ts = Some(ts);
+                    self.client_isn = Some(seq);
+                } else if syn && has_ack && !from_client {
+                    self.syn_ack_ts = Some(ts);
+                    self.server_isn = Some(seq);
+                    self.state = TcpState::SynReceived;
+                }
+            }
+            TcpState::SynReceived => {
+                let expected = self.server_isn.map(|isn| isn.wrapping_add(1));
+                if from_client && has_ack && !syn && Some(ack) == expected {
+                    self.established_ts = Some(ts);
+                    self.state = TcpState::Established;
+                    events.push("established");
+                }
+            }
+            _ => {}
+        }
+
+        if flags & TcpFlags::FIN != 0 {
+            // the FIN occupies the sequence number after the payload
+            let fin_seq = seq.wrapping_add(payload_len as u32);
+            if from_client {
+                self.client_fin = Some(fin_seq);
+            } else {
+                self.server_fin = Some(fin_seq);
+            }
+
+            if self.state != TcpState::HalfClosed {
+                self.state = TcpState::HalfClosed;
+                events.push("half_closed");
+            }
+        }
+
+        if has_ack {
+            let fin = if from_client {
+                self.server_fin
+            } else {
+                self.client_fin
+            };
+
+            if fin.map(|fin| fin.wrapping_add(1)) == Some(ack) {
+                if from_client {
+                    self.server_fin_acked = true;
+                } else {
+                    self.client_fin_acked = true;
+                }
+            }
+        }
+
+        if self.client_fin_acked && self.server_fin_acked {
+            self.state = TcpState::Closed;
+            events.push("closed");
+        }
+
+        events
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionSummary {
+    pub interface: String,
+    pub state: TcpState,
+    pub client: IpAddr,
+    pub client_port: u16,
+    pub server: IpAddr,
+    pub server_port: u16,
+    pub midstream: bool,
+    // handshake round trip times in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_rtt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_rtt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handshake_rtt: Option<f64>,
+    // "client" or "server"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_by: Option<&'static str>,
+    // anomalies in what each side sent
+    pub client_health: TcpHealth,
+    pub server_health: TcpHealth,
+}
+
+#[derive(Serialize)]
+struct TcpStateEvent {
+    r#type: &'static str,
+    event: &'static str,
+    ts: Duration,
+    #[serde(flatten)]
+    connection: ConnectionSummary,
+}
+
+#[derive(Serialize)]
+struct TcpAnomalyEvent<'a> {
+    r#type: &'static str,
+    anomaly: &'static str,
+    // "client" or "server", the side that sent the segment
+    sender: &'static str,
+    interface: &'a str,
+    client: IpAddr,
+    client_port: u16,
+    server: IpAddr,
+    server_port: u16,
+    ts: Duration,
+    seq: u32,
+    ack: u32,
+    len: usize,
+    window: u16,
+    // consecutive duplicates of the same ack
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dup_acks: Option<u32>,
+}
+
+fn send_event(
+    tx: &Sender<OwnedMessage>,
+    event: &'static str,
+    conn: &TcpConnection,
+    interface: &str,
+    ts: Duration,
+) {
+    let event = TcpStateEvent {
+        r#type: "tcp_state",
+        event,
+        ts,
+        connection: conn.summary(interface),
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+fn send_anomaly(
+    tx: &Sender<OwnedMessage>,
+    anomaly: Anomaly,
+    conn: &TcpConnection,
+    from_client: bool,
+    interface: &str,
+    tcp: &TcpPacket,
+    ts: Duration,
+) {
+    let dup_acks = match (anomaly, from_client) {
+        (Anomaly::DupAck, true) => Some(conn.client_side.dup_acks),
+        (Anomaly::DupAck, false) => Some(conn.server_side.dup_acks),
+        _ => None,
+    };
+
+    let event = TcpAnomalyEvent {
+        r#type: "tcp_anomaly",
+        anomaly: anomaly.name(),
+        sender: if from_client { "client" } else { "server" },
+        interface,
+        client: conn.client.0,
+        client_port: conn.client.1,
+        server: conn.server.0,
+        server_port: conn.server.1,
+        ts,
+        seq: tcp.get_sequence(),
+        ack: tcp.get_acknowledgement(),
+        len: tcp.payload().len(),
+        window: tcp.get_window(),
+        dup_acks,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+fn window_scale(tcp: &TcpPacket) -> Option<u8> {
+    tcp.get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::WSCALE)
+        .and_then(|option| option.payload().first().cloned())
+}
+
+// only SYNs and data start tracking, a bare ACK, FIN or RST is more
+// likely the tail of a connection we missed
+fn starts_connection(tcp: &TcpPacket) -> bool {
+    let flags = tcp.get_flags();
+    if flags & (TcpFlags::RST | TcpFlags::FIN) != 0 {
+        return false;
+    }
+
+    flags & TcpFlags::SYN != 0 || !tcp.payload().is_empty()
+}
+
+fn recently_closed(key: &FlowKey, ts: Duration) -> bool {
+    let mut closed = CLOSED.lock().unwrap();
+    let expired = match closed.get(key) {
+        Some(closed_ts) => ts.checked_sub(*closed_ts).unwrap_or_default() >= CLOSE_LINGER,
+        None => return false,
+    };
+
+    if expired {
+        closed.remove(key);
+    }
+    !expired
+}
+
+fn remember_closed(key: &FlowKey, ts: Duration) {
+    let mut closed = CLOSED.lock().unwrap();
+    if closed.len() >= MAX_CLOSED {
+        closed.retain(|_, closed_ts| ts.checked_sub(*closed_ts).unwrap_or_default() < CLOSE_LINGER);
+    }
+    if closed.len() < MAX_CLOSED {
+        closed.insert(key.clone(), ts);
+    }
+}
+
+/// Whether a segment belongs to a tracked connection or may start one,
+/// stray resets and acks and segments after a close are left alone
+pub fn is_tracked(key: &FlowKey, tcp: &TcpPacket, ts: Duration) -> bool {
+    if CONNECTIONS.lock().unwrap().contains_key(key) {
+        return true;
+    }
+
+    // a new SYN on the same ports may follow a close straight away
+    let flags = tcp.get_flags();
+    let new_syn = flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0;
+    if !new_syn && recently_closed(key, ts) {
+        return false;
+    }
+
+    starts_connection(tcp)
+}
+
+/// Feeds a segment to its connection's state machine, ending the flow
+/// once the connection is closed or reset
+pub fn track_connection(
+    key: &FlowKey,
+    source: (IpAddr, u16),
+    destination: (IpAddr, u16),
+    tcp: &TcpPacket,
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) {
+    let flags = tcp.get_flags();
+    let syn = flags & TcpFlags::SYN != 0;
+    let has_ack = flags & TcpFlags::ACK != 0;
+
+    let ended = {
+        let mut connections = CONNECTIONS.lock().unwrap();
+
+        // a new SYN on the same ports starts a new connection
+        let reused = syn
+            && !has_ack
+            && connections
+                .get(key)
+                .map_or(false, |conn| conn.reused_by(tcp.get_sequence()));
+        if reused {
+            connections.remove(key);
+        }
+
+        if !connections.contains_key(key) {
+            if !starts_connection(tcp) {
+                return;
+            }
+
+            let conn = if syn && !has_ack {
+                let mut conn = TcpConnection::new(source, destination, TcpState::SynSent);
+                conn.syn_ts = Some(ts);
+                conn.client_isn = Some(tcp.get_sequence());
+                CLOSED.lock().unwrap().remove(key);
+                send_event(tx, "opened", &conn, &key.interface, ts);
+                conn
+            } else if syn && has_ack {
+                // missed the SYN, the SYN-ACK comes from the server
+                let mut conn = TcpConnection::new(destination, source, TcpState::SynSent);
+                conn.client_isn = Some(tcp.get_acknowledgement().wrapping_sub(1));
+                conn
+            } else {
+                // missed the handshake, guess the lower port is the server
+                let (client, server) = if source.1 < destination.1 {
+                    (destination, source)
+                } else {
+                    (source, destination)
+                };
+
+                let conn = TcpConnection::new(client, server, TcpState::Established);
+                send_event(tx, "established", &conn, &key.interface, ts);
+                conn
+            };
+
+            connections.insert(key.clone(), conn);
+        }
+
+        let conn = connections.get_mut(key).unwrap();
+        let from_client = conn.client == source;
+        let events = conn.update(
+            from_client,
+            flags,
+            tcp.get_sequence(),
+            tcp.get_acknowledgement(),
+            tcp.payload().len(),
+            ts,
+        );
+
+        for event in events {
+            send_event(tx, event, conn, &key.interface, ts);
+        }
+
+        let segment = Segment {
+            seq: tcp.get_sequence(),
+            ack: if has_ack {
+                Some(tcp.get_acknowledgement())
+            } else {
+                None
+            },
+            window: tcp.get_window(),
+            window_scale: if syn { window_scale(tcp) } else { None },
+            payload_len: tcp.payload().len(),
+            syn,
+            fin: flags & TcpFlags::FIN != 0,
+            rst: flags & TcpFlags::RST != 0,
+            ts,
+        };
+
+        for anomaly in conn.analyze(from_client, &segment) {
+            send_anomaly(tx, anomaly, conn, from_client, &key.interface, tcp, ts);
+        }
+
+        match conn.state {
+            TcpState::Closed => Some("closed"),
+            TcpState::Reset => Some("reset"),
+            _ => None,
+        }
+    };
+
+    // forgets the connection through flows::forget_flow
+    if let Some(reason) = ended {
+        remember_closed(key, ts);
+        end_flow(key, reason, tx);
+    }
+}
+
+pub fn current_connections() -> Vec<ConnectionSummary> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, conn)| conn.summary(&key.interface))
+        .collect()
+}
+
+pub fn forget_connection(key: &FlowKey) {
+    CONNECTIONS.lock().unwrap().remove(key);
+}
+
+// capture timestamps can go backwards across interfaces
+fn elapsed(from: Duration, to: Duration) -> Option<Duration> {
+    to.checked_sub(from)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
@@ -0,0 +1,276 @@
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use pnet::packet::Packet;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+use super::config::CONFIG;
+use super::flows::FlowKey;
+use super::tcp::parse_tls_stream;
+
+lazy_static! {
+    static ref STREAMS: Mutex<HashMap<FlowKey, StreamPair>> = Default::default();
+}
+
+/**
+ * This module reassembles each direction of a tcp connection into an
+ * ordered byte stream for the application layer dissectors
+ */
+
+// out of order data held per direction while waiting for a hole to fill
+const MAX_PENDING_BYTES: usize = 256 * 1024;
+// ordered data a parser hasn't consumed yet, eg. a partial record
+const MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// What a parser did with the bytes it was given
+pub enum Parsed {
+    // bytes used up, may run past the buffer to skip data not yet received
+    Consumed(usize),
+    // not interested in the rest of the stream
+    Done,
+}
+
+/// The segment that made new stream data available
+pub struct StreamContext<'a> {
+    pub key: &'a FlowKey,
+    // the side that sent the data
+    pub source: (IpAddr, u16),
+    pub ts: Duration,
+    pub tx: &'a Sender<OwnedMessage>,
+}
+
+/// Reads the start of an ordered stream, called again with whatever
+/// it didn't consume once more data arrives
+pub type StreamParser = fn(&[u8], &StreamContext) -> Parsed;
+
+struct StreamDirection {
+    parser: Option<StreamParser>,
+    // sequence number of the first byte, relative offsets count from here
+    base: Option<u32>,
+    // relative offset of the next byte we expect
+    next: u32,
+    // segments past a hole, by relative offset
+    pending: BTreeMap<u32, Vec<u8>>,
+    pending_bytes: usize,
+    buffer: Vec<u8>,
+    // bytes the parser asked to skip past the buffer
+    skip: usize,
+}
+
+impl StreamDirection {
+    fn new(parser: Option<StreamParser>) -> Self {
+        Self {
+            parser,
+            base: None,
+            next: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            buffer: Vec::new(),
+            skip: 0,
+        }
+    }
+
+    fn add(&mut self, seq: u32, syn: bool, payload: &[u8], context: &StreamContext) {
+        let parser = match self.parser {
+            Some(parser) => parser,
+            None => return,
+        };
+
+        if syn {
+            // the SYN takes up a sequence number
+            self.base = Some(seq.wrapping_add(1));
+        }
+
+        if payload.is_empty() {
+            return;
+        }
+
+        let base = *self.base.get_or_insert(seq);
+        let offset = seq.wrapping_sub(base);
+
+        // retransmitted data we've already delivered
+        let behind = self.next.wrapping_sub(offset) as i32;
+        if behind > 0 {
+            let behind = behind as usize;
+            if behind >= payload.len() {
+                return;
+            }
+            self.deliver(&payload[behind..]);
+        } else if behind == 0 {
+            self.deliver(payload);
+        } else {
+            let hole = offset.wrapping_sub(self.next) as usize;
+            if self.buffer.is_empty() && hole <= self.skip {
+                // the missing bytes were going to be skipped anyway
+                self.skip -= hole;
+                self.next = offset;
+                self.deliver(payload);
+            } else {
+                self.hold(offset, payload);
+            }
+        }
+
+        self.drain_pending();
+
+        if self.pending_bytes > MAX_PENDING_BYTES && !self.skip_hole() {
+            self.give_up();
+            return;
+        }
+
+        self.parse(parser, context);
+    }
+
+    fn deliver(&mut self, data: &[u8]) {
+        self.next = self.next.wrapping_add(data.len() as u32);
+
+        let skipped = cmp::min(self.skip, data.len());
+        self.skip -= skipped;
+        self.buffer.extend_from_slice(&data[skipped..]);
+    }
+
+    fn hold(&mut self, offset: u32, data: &[u8]) {
+        let longer = self
+            .pending
+            .get(&offset)
+            .map_or(true, |held| held.len() < data.len());
+
+        if longer {
+            if let Some(held) = self.pending.insert(offset, data.to_vec()) {
+                self.pending_bytes -= held.len();
+            }
+            self.pending_bytes += data.len();
+        }
+    }
+
+    // delivers held segments that are now contiguous
+    fn drain_pending(&mut self) {
+        loop {
+            let offset = match self.pending.keys().next() {
+                Some(offset) => *offset,
+                None => return,
+            };
+
+            let behind = self.next.wrapping_sub(offset) as i32;
+            if behind < 0 {
+                return;
+            }
+
+            let data = self.pending.remove(&offset).unwrap();
+            self.pending_bytes -= data.len();
+
+            if (behind as usize) < data.len() {
+                self.deliver(&data[behind as usize..]);
+            }
+        }
+    }
+
+    // jumps over a hole that's not going to fill, true if the parser can
+    // carry on because it was skipping those bytes anyway
+    fn skip_hole(&mut self) -> bool {
+        let offset = match self.pending.keys().next() {
+            Some(offset) => *offset,
+            None => return true,
+        };
+
+        let hole = offset.wrapping_sub(self.next) as usize;
+        let resumable = self.buffer.is_empty() && hole <= self.skip;
+
+        self.skip = self.skip.saturating_sub(hole);
+        self.next = offset;
+        self.drain_pending();
+
+        resumable
+    }
+
+    fn parse(&mut self, parser: StreamParser, context: &StreamContext) {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        match parser(&self.buffer, context) {
+            Parsed::Consumed(consumed) => {
+                if consumed >= self.buffer.len() {
+                    self.skip += consumed - self.buffer.len();
+                    self.buffer.clear();
+                } else {
+                    self.buffer.drain(..consumed);
+                }
+
+                if self.buffer.len() > MAX_BUFFERED_BYTES {
+                    self.give_up();
+                }
+            }
+            Parsed::Done => self.give_up(),
+        }
+    }
+
+    fn give_up(&mut self) {
+        self.parser = None;
+        self.pending.clear();
+        self.pending_bytes = 0;
+        self.buffer = Vec::new();
+    }
+}
+
+// the directions follow the FlowKey's lower and upper endpoints
+struct StreamPair {
+    from_lower: StreamDirection,
+    from_upper: StreamDirection,
+}
+
+fn stream_parser() -> Option<StreamParser> {
+    if CONFIG.dissectors.tls {
+        Some(parse_tls_stream)
+    } else {
+        None
+    }
+}
+
+/// Adds a segment's payload to its direction of the stream, running
+/// the parser over any newly contiguous bytes
+pub fn reassemble_segment(
+    key: &FlowKey,
+    source: (IpAddr, u16),
+    tcp: &TcpPacket,
+    ts: Duration,
+    tx: &Sender<OwnedMessage>,
+) {
+    let flags = tcp.get_flags();
+    let syn = flags & TcpFlags::SYN != 0;
+    let payload = tcp.payload();
+
+    if stream_parser().is_none() || (!syn && payload.is_empty()) {
+        return;
+    }
+
+    let mut streams = STREAMS.lock().unwrap();
+    let stream = streams.entry(key.clone()).or_insert_with(|| StreamPair {
+        from_lower: StreamDirection::new(stream_parser()),
+        from_upper: StreamDirection::new(stream_parser()),
+    });
+
+    let direction = if source == key.lower {
+        &mut stream.from_lower
+    } else {
+        &mut stream.from_upper
+    };
+
+    let context = StreamContext {
+        key,
+        source,
+        ts,
+        tx,
+    };
+
+    direction.add(tcp.get_sequence(), syn, payload, &context);
+}
+
+pub fn forget_stream(key: &FlowKey) {
+    STREAMS.lock().unwrap().remove(key);
+}
@@ -0,0 +1,348 @@
+use serde::Serialize;
+
+use std::cmp;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tls_parser::{
+    TlsCipherSuite, TlsClientHelloContents, TlsExtension, TlsServerHelloContents, TlsVersion,
+};
+
+use super::tls_alert::TlsAlert;
+use super::tls_anomaly::TlsAnomalyReport;
+use super::x509::CertificateInfo;
+
+/**
+ * This module keeps what a tls handshake negotiated, from the
+ * ClientHello offer and the ServerHello answer
+ */
+
+/// https://tools.ietf.org/html/draft-davidben-tls-grease-00
+pub const GREASE_TABLE: &[u16] = &[
+    0x0a0a, 0x1a1a, 0x2a2a, 0x3a3a, 0x4a4a, 0x5a5a, 0x6a6a, 0x7a7a, 0x8a8a, 0x9a9a, 0xaaaa, 0xbaba,
+    0xcaca, 0xdada, 0xeaea, 0xfafa,
+];
+
+// a ServerHello with this random is a HelloRetryRequest, RFC 8446 4.1.3
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+pub fn is_grease(value: u16) -> bool {
+    GREASE_TABLE.iter().any(|g| *g == value)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TlsHandshake {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    // highest version the client offered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    // negotiated version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub offered_ciphers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<String>,
+    pub offered_alpn: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn: Option<String>,
+    // groups the client sent key shares for, and the one the server picked
+    pub key_share_groups: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_share_group: Option<String>,
+    pub signature_algorithms: Vec<String>,
+    pub session_ticket_offered: bool,
+    pub psk_offered: bool,
+    pub psk_accepted: bool,
+    // abbreviated handshake, by PSK or a tls 1.2 session id
+    pub resumed: bool,
+    pub hello_retry: bool,
+    // md5 of the JA3/JA3S strings, and JA4/JA4S
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja3: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja4: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja3s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ja4s: Option<String>,
+    // the server's chain as sent, leaf first, only visible before tls 1.3
+    pub certificates: Vec<CertificateInfo>,
+    pub alerts: Vec<TlsAlert>,
+    pub anomalies: Vec<TlsAnomalyReport>,
+    // the server sent renegotiation_info, RFC 5746
+    pub secure_renegotiation: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_hello_ts: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_hello_ts: Option<Duration>,
+    #[serde(skip)]
+    pub client_version_id: u16,
+    #[serde(skip)]
+    pub version_id: u16,
+    #[serde(skip)]
+    pub cipher_id: Option<u16>,
+    // supported_versions from the ClientHello, empty without the extension
+    #[serde(skip)]
+    pub offered_versions: Vec<u16>,
+    #[serde(skip)]
+    pub offered_cipher_ids: Vec<u16>,
+    #[serde(skip)]
+    session_id: Vec<u8>,
+}
+
+impl TlsHandshake {
+    /// Records the client's offer, a second ClientHello after a
+    /// HelloRetryRequest replaces the first
+    pub fn client_hello(
+        &mut self,
+        hello: &TlsClientHelloContents,
+        extensions: &[TlsExtension],
+        client: (IpAddr, u16),
+        server: (IpAddr, u16),
+        ts: Duration,
+    ) {
+        self.client = Some(client.0);
+        self.client_port = Some(client.1);
+        self.server = Some(server.0);
+        self.server_port = Some(server.1);
+        self.client_hello_ts = Some(ts);
+        self.session_id = hello.session_id.unwrap_or(&[]).to_vec();
+
+        self.offered_cipher_ids = hello
+            .ciphers
+            .iter()
+            .map(|cipher| cipher.0)
+            .filter(|id| !is_grease(*id))
+            .collect();
+        self.offered_ciphers = self
+            .offered_cipher_ids
+            .iter()
+            .map(|id| cipher_name(*id))
+            .collect();
+
+        self.offered_alpn.clear();
+        self.key_share_groups.clear();
+        self.signature_algorithms.clear();
+        self.offered_versions.clear();
+        self.session_ticket_offered = false;
+        self.psk_offered = false;
+
+        let mut highest = hello.version.0;
+
+        for ext in extensions {
+            match ext {
+                TlsExtension::SNI(names) => {
+                    self.sni = names
+                        .iter()
+                        .filter_map(|(_, name)| std::str::from_utf8(name).ok())
+                        .next()
+                        .map(|name| name.to_owned());
+                }
+                TlsExtension::ALPN(protocols) => {
+                    self.offered_alpn = protocols.iter().map(|p| protocol_name(p)).collect();
+                }
+                TlsExtension::SignatureAlgorithms(algorithms) => {
+                    self.signature_algorithms = algorithms
+                        .iter()
+                        .filter(|id| !is_grease(**id))
+                        .map(|id| signature_algorithm_name(*id))
+                        .collect();
+                }
+                TlsExtension::KeyShare(shares) => {
+                    self.key_share_groups = client_key_share_groups(shares)
+                        .into_iter()
+                        .filter(|id| !is_grease(*id))
+                        .map(group_name)
+                        .collect();
+                }
+                TlsExtension::SupportedVersions(versions) => {
+                    highest = highest_version(highest, versions);
+                    self.offered_versions = versions
+                        .iter()
+                        .map(|version| version.0)
+                        .filter(|version| !is_grease(*version))
+                        .collect();
+                }
+                TlsExtension::SessionTicket(ticket) => {
+                    self.session_ticket_offered = !ticket.is_empty();
+                }
+                TlsExtension::PreSharedKey(_) => {
+                    self.psk_offered = true;
+                }
+                _ => {}
+            }
+        }
+
+        self.client_version_id = highest;
+        self.client_version = Some(version_name(highest));
+    }
+
+    /// Records the server's choices, returns true for a HelloRetryRequest
+    pub fn server_hello(
+        &mut self,
+        hello: &TlsServerHelloContents,
+        extensions: &[TlsExtension],
+        ts: Duration,
+    ) -> bool {
+        self.server_hello_ts = Some(ts);
+        self.cipher_id = Some(hello.cipher.0);
+        self.cipher = Some(cipher_name(hello.cipher.0));
+
+        let mut version = hello.version.0;
+        let mut psk_accepted = false;
+
+        for ext in extensions {
+            match ext {
+                TlsExtension::SupportedVersions(versions) => {
+                    version = highest_version(version, versions);
+                }
+                TlsExtension::ALPN(protocols) => {
+                    self.alpn = protocols.first().map(|p| protocol_name(p));
+                }
+                TlsExtension::KeyShare(share) => {
+                    // the selected group leads both the ServerHello and HelloRetryRequest form
+                    if share.len() >= 2 {
+                        self.key_share_group =
+                            Some(group_name(u16::from_be_bytes([share[0], share[1]])));
+                    }
+                }
+                TlsExtension::PreSharedKey(_) => {
+                    psk_accepted = true;
+                }
+                TlsExtension::RenegotiationInfo(_) => {
+                    self.secure_renegotiation = true;
+                }
+                _ => {}
+            }
+        }
+
+        self.version_id = version;
+        self.version = Some(version_name(version));
+
+        if server_random(hello)[..] == HELLO_RETRY_RANDOM[..] {
+            self.hello_retry = true;
+            return true;
+        }
+
+        // tls 1.3 echoes the session id for middleboxes, only psk means resumption
+        let session_resumed = version < TlsVersion::Tls13.0
+            && !self.session_id.is_empty()
+            && hello.session_id == Some(&self.session_id[..]);
+
+        self.psk_accepted = psk_accepted;
+        self.resumed = psk_accepted || session_resumed;
+
+        false
+    }
+}
+
+/// The 32 byte random, tls-parser splits off the first 4 bytes
+pub fn server_random(hello: &TlsServerHelloContents) -> Vec<u8> {
+    let mut random = hello.rand_time.to_be_bytes().to_vec();
+    random.extend_from_slice(hello.rand_data);
+    random
+}
+
+pub fn highest_version(highest: u16, versions: &[TlsVersion]) -> u16 {
+    versions
+        .iter()
+        .map(|version| version.0)
+        .filter(|version| !is_grease(*version))
+        .fold(highest, cmp::max)
+}
+
+pub fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSL 3.0".to_owned(),
+        0x0301 => "TLS 1.0".to_owned(),
+        0x0302 => "TLS 1.1".to_owned(),
+        0x0303 => "TLS 1.2".to_owned(),
+        0x0304 => "TLS 1.3".to_owned(),
+        _ => format!("0x{:04x}", version),
+    }
+}
+
+pub fn cipher_name(id: u16) -> String {
+    match TlsCipherSuite::from_id(id) {
+        Some(cipher) => cipher.name.to_owned(),
+        None => format!("0x{:04x}", id),
+    }
+}
+
+pub fn group_name(id: u16) -> String {
+    let name = match id {
+        0x0017 => "secp256r1",
+        0x0018 => "secp384r1",
+        0x0019 => "secp521r1",
+        0x001d => "x25519",
+        0x001e => "x448",
+        0x0100 => "ffdhe2048",
+        0x0101 => "ffdhe3072",
+        0x0102 => "ffdhe4096",
+        0x0103 => "ffdhe6144",
+        0x0104 => "ffdhe8192",
+        0x11eb => "SecP256r1MLKEM768",
+        0x11ec => "X25519MLKEM768",
+        0x11ed => "SecP384r1MLKEM1024",
+        0x6399 => "X25519Kyber768Draft00",
+        _ => return format!("0x{:04x}", id),
+    };
+
+    name.to_owned()
+}
+
+pub fn signature_algorithm_name(id: u16) -> String {
+    let name = match id {
+        0x0201 => "rsa_pkcs1_sha1",
+        0x0203 => "ecdsa_sha1",
+        0x0401 => "rsa_pkcs1_sha256",
+        0x0403 => "ecdsa_secp256r1_sha256",
+        0x0501 => "rsa_pkcs1_sha384",
+        0x0503 => "ecdsa_secp384r1_sha384",
+        0x0601 => "rsa_pkcs1_sha512",
+        0x0603 => "ecdsa_secp521r1_sha512",
+        0x0804 => "rsa_pss_rsae_sha256",
+        0x0805 => "rsa_pss_rsae_sha384",
+        0x0806 => "rsa_pss_rsae_sha512",
+        0x0807 => "ed25519",
+        0x0808 => "ed448",
+        0x0809 => "rsa_pss_pss_sha256",
+        0x080a => "rsa_pss_pss_sha384",
+        0x080b => "rsa_pss_pss_sha512",
+        _ => return format!("0x{:04x}", id),
+    };
+
+    name.to_owned()
+}
+
+fn protocol_name(protocol: &[u8]) -> String {
+    String::from_utf8_lossy(protocol).into_owned()
+}
+
+// KeyShareClientHello: a u16 length, then group, u16 length, key exchange
+fn client_key_share_groups(shares: &[u8]) -> Vec<u16> {
+    let mut groups = Vec::new();
+    let mut offset = 2;
+
+    while offset + 4 <= shares.len() {
+        let group = u16::from_be_bytes([shares[offset], shares[offset + 1]]);
+        let len = u16::from_be_bytes([shares[offset + 2], shares[offset + 3]]) as usize;
+
+        groups.push(group);
+        offset += 4 + len;
+    }
+
+    groups
+}
@@ -0,0 +1,219 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use websocket::message::OwnedMessage;
+
+lazy_static! {
+    static ref ALERT_SUMMARIES: Mutex<HashMap<String, AlertSummary>> = Default::default();
+}
+
+/**
+ * This module records the alerts tls peers send, so handshakes failing
+ * on certificates or protocol versions show up per server
+ */
+
+const ALERT_FATAL: u8 = 2;
+// servers kept, the least recently seen goes first
+const MAX_ALERT_SUMMARIES: usize = 10000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsAlert {
+    // warning or fatal
+    pub level: String,
+    pub description: String,
+    // client or server
+    pub side: &'static str,
+    // the last handshake message seen before the alert
+    pub stage: &'static str,
+    pub ts: Duration,
+}
+
+impl TlsAlert {
+    pub fn new(
+        level: u8,
+        description: u8,
+        side: &'static str,
+        stage: &'static str,
+        ts: Duration,
+    ) -> Self {
+        Self {
+            level: level_name(level),
+            description: description_name(description),
+            side,
+            stage,
+            ts,
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        self.level == "fatal"
+    }
+}
+
+/// Alerts seen for one server, by SNI or by address without one
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertSummary {
+    pub server: String,
+    pub total: u64,
+    pub fatal: u64,
+    pub from_client: u64,
+    pub from_server: u64,
+    pub descriptions: HashMap<String, u64>,
+    pub stages: HashMap<&'static str, u64>,
+    pub last_seen: Duration,
+}
+
+impl AlertSummary {
+    fn new(server: String) -> Self {
+        Self {
+            server,
+            total: 0,
+            fatal: 0,
+            from_client: 0,
+            from_server: 0,
+            descriptions: HashMap::new(),
+            stages: HashMap::new(),
+            last_seen: Duration::new(0, 0),
+        }
+    }
+
+    fn add(&mut self, alert: &TlsAlert) {
+        self.total += 1;
+        if alert.is_fatal() {
+            self.fatal += 1;
+        }
+        if alert.side == "client" {
+            self.from_client += 1;
+        } else {
+            self.from_server += 1;
+        }
+
+        *self
+            .descriptions
+            .entry(alert.description.clone())
+            .or_insert(0) += 1;
+        *self.stages.entry(alert.stage).or_insert(0) += 1;
+        self.last_seen = alert.ts;
+    }
+}
+
+#[derive(Serialize)]
+struct TlsAlertEvent<'a> {
+    r#type: &'static str,
+    client: IpAddr,
+    client_port: u16,
+    server: IpAddr,
+    server_port: u16,
+    sni: &'a Option<String>,
+    #[serde(flatten)]
+    alert: &'a TlsAlert,
+}
+
+/// Adds an alert to its server's summary and tells the clients about it
+pub fn record_alert(
+    client: (IpAddr, u16),
+    server: (IpAddr, u16),
+    sni: &Option<String>,
+    alert: &TlsAlert,
+    tx: &Sender<OwnedMessage>,
+) {
+    let name = match sni {
+        Some(sni) => sni.clone(),
+        None => server.0.to_string(),
+    };
+
+    {
+        let mut summaries = ALERT_SUMMARIES.lock().unwrap();
+        if !summaries.contains_key(&name) && summaries.len() >= MAX_ALERT_SUMMARIES {
+            let oldest = summaries
+                .values()
+                .min_by_key(|summary| summary.last_seen)
+                .map(|summary| summary.server.clone());
+            if let Some(oldest) = oldest {
+                summaries.remove(&oldest);
+            }
+        }
+
+        summaries
+            .entry(name.clone())
+            .or_insert_with(|| AlertSummary::new(name))
+            .add(alert);
+    }
+
+    let event = TlsAlertEvent {
+        r#type: "tls_alert",
+        client: client.0,
+        client_port: client.1,
+        server: server.0,
+        server_port: server.1,
+        sni,
+        alert,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
+
+/// Servers with the most alerts first
+pub fn alert_summaries() -> Vec<AlertSummary> {
+    let mut summaries: Vec<AlertSummary> =
+        ALERT_SUMMARIES.lock().unwrap().values().cloned().collect();
+    summaries.sort_by(|a, b| b.total.cmp(&a.total));
+    summaries
+}
+
+fn level_name(level: u8) -> String {
+    match level {
+        1 => "warning".to_owned(),
+        ALERT_FATAL => "fatal".to_owned(),
+        _ => level.to_string(),
+    }
+}
+
+/// https://www.iana.org/assignments/tls-parameters/tls-parameters.xhtml#tls-parameters-6
+fn description_name(description: u8) -> String {
+    let name = match description {
+        0 => "close_notify",
+        10 => "unexpected_message",
+        20 => "bad_record_mac",
+        21 => "decryption_failed",
+        22 => "record_overflow",
+        30 => "decompression_failure",
+        40 => "handshake_failure",
+        41 => "no_certificate",
+        42 => "bad_certificate",
+        43 => "unsupported_certificate",
+        44 => "certificate_revoked",
+        45 => "certificate_expired",
+        46 => "certificate_unknown",
+        47 => "illegal_parameter",
+        48 => "unknown_ca",
+        49 => "access_denied",
+        50 => "decode_error",
+        51 => "decrypt_error",
+        60 => "export_restriction",
+        70 => "protocol_version",
+        71 => "insufficient_security",
+        80 => "internal_error",
+        86 => "inappropriate_fallback",
+        90 => "user_canceled",
+        100 => "no_renegotiation",
+        109 => "missing_extension",
+        110 => "unsupported_extension",
+        111 => "certificate_unobtainable",
+        112 => "unrecognized_name",
+        113 => "bad_certificate_status_response",
+        114 => "bad_certificate_hash_value",
+        115 => "unknown_psk_identity",
+        116 => "certificate_required",
+        120 => "no_application_protocol",
+        _ => return description.to_string(),
+    };
+
+    name.to_owned()
+}
@@ -0,0 +1,172 @@
+use serde::Serialize;
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use tls_parser::{TlsServerHelloContents, TlsVersion};
+use websocket::message::OwnedMessage;
+
+use super::tls::{cipher_name, server_random, version_name, TlsHandshake};
+
+/**
+ * This module checks negotiated tls parameters for downgrades, choices
+ * the client never offered, obsolete protocol versions and weak ciphers,
+ * publishing them as tls_anomaly events
+ */
+
+// the last 8 bytes of ServerHello.random from a tls 1.3 capable server
+// negotiating an older version, RFC 8446 4.1.3
+const DOWNGRADE_TLS12: [u8; 8] = [0x44, 0x4f, 0x57, 0x4e, 0x47, 0x52, 0x44, 0x01];
+const DOWNGRADE_TLS11: [u8; 8] = [0x44, 0x4f, 0x57, 0x4e, 0x47, 0x52, 0x44, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsAnomaly {
+    DowngradeSentinel,
+    VersionNotOffered,
+    CipherNotOffered,
+    // SSL 3.0 or TLS 1.0
+    ObsoleteVersion,
+    WeakCipher,
+    Renegotiation,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsAnomalyReport {
+    pub anomaly: TlsAnomaly,
+    pub detail: String,
+    pub ts: Duration,
+}
+
+impl TlsAnomalyReport {
+    pub fn new(anomaly: TlsAnomaly, detail: String, ts: Duration) -> Self {
+        Self {
+            anomaly,
+            detail,
+            ts,
+        }
+    }
+}
+
+/// Compares the server's choices with the client's offer
+pub fn check_server_hello(
+    handshake: &TlsHandshake,
+    hello: &TlsServerHelloContents,
+    ts: Duration,
+) -> Vec<TlsAnomalyReport> {
+    let mut reports = Vec::new();
+    let version = handshake.version_id;
+    let client_offered_tls13 = handshake.client_version_id >= TlsVersion::Tls13.0;
+
+    // servers set the sentinel for every older client, it only means a
+    // downgrade when the client offered tls 1.3 and should have aborted
+    let random = server_random(hello);
+    let sentinel = &random[random.len() - 8..];
+    if client_offered_tls13 && (sentinel == DOWNGRADE_TLS12 || sentinel == DOWNGRADE_TLS11) {
+        reports.push(TlsAnomalyReport::new(
+            TlsAnomaly::DowngradeSentinel,
+            format!(
+                "server supports TLS 1.3 but negotiated {} with a client offering TLS 1.3",
+                version_name(version)
+            ),
+            ts,
+        ));
+    }
+
+    // without supported_versions any version up to the client's is allowed
+    let version_offered = if handshake.offered_versions.is_empty() {
+        version <= handshake.client_version_id
+    } else {
+        handshake.offered_versions.contains(&version)
+    };
+    if !version_offered {
+        reports.push(TlsAnomalyReport::new(
+            TlsAnomaly::VersionNotOffered,
+            format!(
+                "{} chosen, client offered at most {}",
+                version_name(version),
+                version_name(handshake.client_version_id)
+            ),
+            ts,
+        ));
+    }
+
+    if version <= TlsVersion::Tls10.0 {
+        reports.push(TlsAnomalyReport::new(
+            TlsAnomaly::ObsoleteVersion,
+            version_name(version),
+            ts,
+        ));
+    }
+
+    let cipher = hello.cipher.0;
+    if !handshake.offered_cipher_ids.contains(&cipher) {
+        reports.push(TlsAnomalyReport::new(
+            TlsAnomaly::CipherNotOffered,
+            cipher_name(cipher),
+            ts,
+        ));
+    }
+
+    let name = cipher_name(cipher);
+    if let Some(weakness) = cipher_weakness(&name) {
+        reports.push(TlsAnomalyReport::new(
+            TlsAnomaly::WeakCipher,
+            format!("{} ({})", name, weakness),
+            ts,
+        ));
+    }
+
+    reports
+}
+
+/// Why a cipher suite shouldn't be used, by its IANA name
+pub fn cipher_weakness(name: &str) -> Option<&'static str> {
+    if name.contains("EXPORT") {
+        Some("export")
+    } else if name.contains("_NULL_") || name.ends_with("_NULL") {
+        Some("no encryption")
+    } else if name.contains("_anon_") {
+        Some("anonymous")
+    } else if name.contains("_RC4_") {
+        Some("rc4")
+    } else if name.contains("_DES_") || name.contains("_DES40_") || name.contains("3DES") {
+        Some("des")
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct TlsAnomalyEvent<'a> {
+    r#type: &'static str,
+    client: IpAddr,
+    client_port: u16,
+    server: IpAddr,
+    server_port: u16,
+    sni: &'a Option<String>,
+    #[serde(flatten)]
+    report: &'a TlsAnomalyReport,
+}
+
+pub fn send_anomaly(
+    tx: &Sender<OwnedMessage>,
+    client: (IpAddr, u16),
+    server: (IpAddr, u16),
+    sni: &Option<String>,
+    report: &TlsAnomalyReport,
+) {
+    let event = TlsAnomalyEvent {
+        r#type: "tls_anomaly",
+        client: client.0,
+        client_port: client.1,
+        server: server.0,
+        server_port: server.1,
+        sni,
+        report,
+    };
+
+    let payload = serde_json::to_string(&event).unwrap();
+    tx.send(OwnedMessage::Text(payload)).unwrap();
+}
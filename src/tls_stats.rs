@@ -0,0 +1,103 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::histogram::LatencyHistogram;
+use super::tls::TlsHandshake;
+
+lazy_static! {
+    static ref TLS_STATS: Mutex<TlsStats> = Default::default();
+}
+
+/**
+ * This module keeps running totals of what tls handshakes negotiated
+ * and how long they took, updated as each handshake is seen
+ */
+
+/// Handshake latencies for one negotiated version
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VersionLatency {
+    // ClientHello to ServerHello
+    pub server_hello: LatencyHistogram,
+    // ClientHello to the first application data record, after the
+    // encrypted handshake records with tls 1.3
+    pub application_data: LatencyHistogram,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TlsStats {
+    pub client_hellos: u64,
+    pub server_hellos: u64,
+    // highest version each client offered
+    pub client_versions: HashMap<String, u64>,
+    pub versions: HashMap<String, u64>,
+    pub ciphers: HashMap<String, u64>,
+    // negotiated protocol, "none" without ALPN
+    pub alpn: HashMap<String, u64>,
+    pub latency: HashMap<String, VersionLatency>,
+}
+
+fn increment(counts: &mut HashMap<String, u64>, key: &Option<String>) {
+    let key = key.clone().unwrap_or_else(|| "none".to_owned());
+    *counts.entry(key).or_insert(0) += 1;
+}
+
+/// Counts the first ClientHello of a connection
+pub fn client_hello(handshake: &TlsHandshake) {
+    let mut stats = TLS_STATS.lock().unwrap();
+    stats.client_hellos += 1;
+    increment(&mut stats.client_versions, &handshake.client_version);
+}
+
+/// Counts what the server chose, with the latency if we saw the ClientHello
+pub fn server_hello(handshake: &TlsHandshake) {
+    let mut stats = TLS_STATS.lock().unwrap();
+    stats.server_hellos += 1;
+    increment(&mut stats.versions, &handshake.version);
+    increment(&mut stats.ciphers, &handshake.cipher);
+    increment(&mut stats.alpn, &handshake.alpn);
+
+    if let (Some(version), Some(latency)) = (&handshake.version, handshake_latency(handshake)) {
+        stats
+            .latency
+            .entry(version.clone())
+            .or_insert_with(Default::default)
+            .server_hello
+            .add(latency);
+    }
+}
+
+/// Adds the time from ClientHello to the first application data
+pub fn application_data(handshake: &TlsHandshake, latency: Duration) {
+    let version = match &handshake.version {
+        Some(version) => version.clone(),
+        None => return,
+    };
+
+    TLS_STATS
+        .lock()
+        .unwrap()
+        .latency
+        .entry(version)
+        .or_insert_with(Default::default)
+        .application_data
+        .add(latency);
+}
+
+fn handshake_latency(handshake: &TlsHandshake) -> Option<Duration> {
+    let client_hello = handshake.client_hello_ts?;
+    let server_hello = handshake.server_hello_ts?;
+    // capture timestamps can go backwards across interfaces
+    server_hello.checked_sub(client_hello)
+}
+
+pub fn tls_stats() -> TlsStats {
+    let mut stats = TLS_STATS.lock().unwrap().clone();
+    for latency in stats.latency.values_mut() {
+        latency.server_hello.update_percentiles();
+        latency.application_data.update_percentiles();
+    }
+    stats
+}
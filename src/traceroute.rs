@@ -1,7 +1,7 @@
 use pnet::packet::ip::IpNextHeaderProtocols;
 // use pnet::packet::udp::MutableUdpPacket;
 use pnet::packet::icmp::echo_reply::EchoReplyPacket;
-use pnet::packet::icmp::echo_request::MutableEchoRequestPacket;
+use pnet::packet::icmp::echo_request::{EchoRequestPacket, MutableEchoRequestPacket};
 use pnet::packet::icmp::time_exceeded::TimeExceededPacket;
 use pnet::packet::icmp::IcmpTypes;
 use pnet::packet::Packet;
@@ -15,7 +15,7 @@ use rand::random;
 use std::fmt;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -38,7 +38,7 @@ pub struct Prober {
     trace_routes: HashMap<IpAddr, Traceroute>,
 
     // map all addresses - look up individual nodes, gather ttl, avg loss, rtt
-    prober: IcmpProber, // transport implementation
+    prober: Option<IcmpProber>, // transport implementation, needs raw socket privileges
 
     // callback: Box<FnMut()>,
     tx: Option<Sender<OwnedMessage>>,
@@ -55,7 +55,7 @@ impl Prober {
         Prober {
             outgoing_probes: Default::default(),
             trace_routes: Default::default(),
-            prober: IcmpProber::setup().unwrap(),
+            prober: IcmpProber::setup(),
             tx: None,
         }
     }
@@ -83,11 +83,26 @@ impl Prober {
         // register probe
         let probe = Probe::new(addr, ttl);
         let key = probe.outgoing_key().clone();
-        self.prober.ping_with_ttl(probe);
+        match self.prober {
+            Some(ref mut prober) => prober.ping_with_ttl(probe),
+            None => {
+                println!("Can't send probes without a raw socket");
+                return;
+            }
+        }
         self.outgoing_probes.insert(key, probe);
     }
 
-    pub fn handle_ping_id(&mut self, source: IpAddr, ping_id: u16, seq_id: u16) {
+    /// Replaces the send time with the capture timestamp of the probe
+    pub fn handle_sent_probe(&mut self, ping_id: u16, seq_id: u16, ts: Duration) {
+        let key = Probe::format_key(ping_id, seq_id);
+
+        if let Some(probe) = self.outgoing_probes.get_mut(&key) {
+            probe.sent_time = ts;
+        }
+    }
+
+    pub fn handle_ping_id(&mut self, source: IpAddr, ping_id: u16, seq_id: u16, ts: Duration) {
         let key = Probe::format_key(ping_id, seq_id);
 
         if let Some(probe) = self.outgoing_probes.remove(&key) {
@@ -100,12 +115,12 @@ impl Prober {
 
             // add results
             self.trace_routes.get_mut(&probe.addr).map(|trace| {
-                trace.receive_probe(source, probe);
+                trace.receive_probe(source, probe, ts);
 
                 // send results over websockets
                 if trace.hop_reached {
                     if let Some(tx) = tx {
-                        let info = TraceRouteInfo::new(trace.probes.clone(), probe.addr);
+                        let info = TraceRouteInfo::new(trace.probes.clone(), probe.addr, ts);
                         let payload = serde_json::to_string(&info).unwrap();
                         tx.send(OwnedMessage::Text(payload)).unwrap();
                     }
@@ -147,7 +162,7 @@ impl Traceroute {
         self.hop_reached = false;
     }
 
-    fn receive_probe(&mut self, source: IpAddr, probe: Probe) {
+    fn receive_probe(&mut self, source: IpAddr, probe: Probe, ts: Duration) {
         if probe.ttl > self.max_hop {
             return;
         }
@@ -160,7 +175,7 @@ impl Traceroute {
         // TODO clean up big ttls
         self.probes.push(ProbeResult::Receive {
             addr: source,
-            rtt: Instant::now().duration_since(probe.sent_time),
+            rtt: ts.checked_sub(probe.sent_time).unwrap_or_default(),
             hop: probe.ttl,
         });
 
@@ -181,7 +196,8 @@ struct Probe {
     ping_id: u16,
     sequence_id: u16,
     ttl: u8,
-    sent_time: Instant,
+    // since the unix epoch, to compare with capture timestamps
+    sent_time: Duration,
     addr: IpAddr,
 }
 
@@ -192,7 +208,9 @@ impl Probe {
             ping_id: random::<u16>(),
             sequence_id: random::<u16>(),
             ttl,
-            sent_time: Instant::now(),
+            sent_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
         }
     }
 
@@ -220,14 +238,17 @@ struct TraceRouteInfo {
     data: Vec<ProbeResult>,
     destination: IpAddr,
     r#type: String,
+    // capture time of the latest reply
+    ts: Duration,
 }
 
 impl TraceRouteInfo {
-    fn new(data: Vec<ProbeResult>, destination: IpAddr) -> Self {
+    fn new(data: Vec<ProbeResult>, destination: IpAddr, ts: Duration) -> Self {
         Self {
             data,
             r#type: String::from("traceroute"),
             destination,
+            ts,
         }
     }
 }
@@ -295,7 +316,13 @@ impl IcmpProber {
     pub fn setup() -> Option<IcmpProber> {
         let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
 
-        let (tx, rx) = transport_channel(4096, protocol).unwrap();
+        let (tx, rx) = match transport_channel(4096, protocol) {
+            Ok(channel) => channel,
+            Err(e) => {
+                println!("Can't open icmp channel, traceroute disabled: {}", e);
+                return None;
+            }
+        };
         // let (mut tx, mut rx) = match transport_channel(4096, protocol) {
         //     Ok((tx, rx)) => (tx, rx),
         //     Err(e) => panic!(
@@ -341,7 +368,11 @@ impl IcmpProber {
     }
 }
 
-pub fn handle_time_exceeded(source: IpAddr, time_exceeded_packet: TimeExceededPacket) {
+pub fn handle_time_exceeded(
+    source: IpAddr,
+    time_exceeded_packet: TimeExceededPacket,
+    ts: Duration,
+) {
     let payload = time_exceeded_packet.payload();
 
     // let ttl = payload[8];
@@ -373,13 +404,22 @@ pub fn handle_time_exceeded(source: IpAddr, time_exceeded_packet: TimeExceededPa
     PROBER
         .write()
         .unwrap()
-        .handle_ping_id(source, ping_id, seq_id);
+        .handle_ping_id(source, ping_id, seq_id, ts);
 }
 
-pub fn handle_echo_reply(source: IpAddr, echo_reply: EchoReplyPacket) {
+pub fn handle_echo_reply(source: IpAddr, echo_reply: EchoReplyPacket, ts: Duration) {
     PROBER.write().unwrap().handle_ping_id(
         source,
         echo_reply.get_identifier(),
         echo_reply.get_sequence_number(),
+        ts,
+    );
+}
+
+pub fn handle_echo_request(echo_request: EchoRequestPacket, ts: Duration) {
+    PROBER.write().unwrap().handle_sent_probe(
+        echo_request.get_identifier(),
+        echo_request.get_sequence_number(),
+        ts,
     );
 }
@@ -0,0 +1,113 @@
+use pnet::packet::ethernet::EtherType;
+
+use super::link_layer::decode_ethernet;
+
+/**
+ * This module strips overlay headers (GRE, VXLAN, Geneve)
+ * so the encapsulated traffic can be dissected
+ */
+
+pub const VXLAN_PORT: u16 = 4789;
+pub const GENEVE_PORT: u16 = 6081;
+
+// transparent ethernet bridging, an ethernet frame follows
+const ETHERTYPE_TEB: u16 = 0x6558;
+
+const GRE_HEADER_LEN: usize = 4;
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+const VXLAN_HEADER_LEN: usize = 8;
+const VXLAN_VNI_VALID: u8 = 0x08;
+
+const GENEVE_HEADER_LEN: usize = 8;
+
+pub struct Decapsulated<'a> {
+    // gre key or vxlan/geneve network identifier
+    pub id: Option<u32>,
+    pub ether_type: EtherType,
+    pub payload: &'a [u8],
+}
+
+pub fn decode_gre(packet: &[u8]) -> Option<Decapsulated> {
+    if packet.len() < GRE_HEADER_LEN {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([packet[0], packet[1]]);
+    let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+
+    // version 1 is pptp, which carries ppp
+    if flags & GRE_VERSION != 0 {
+        return None;
+    }
+
+    let mut offset = GRE_HEADER_LEN;
+    if flags & GRE_CHECKSUM != 0 {
+        // checksum and reserved
+        offset += 4;
+    }
+
+    let mut key = None;
+    if flags & GRE_KEY != 0 {
+        let k = packet.get(offset..offset + 4)?;
+        key = Some(u32::from_be_bytes([k[0], k[1], k[2], k[3]]));
+        offset += 4;
+    }
+
+    if flags & GRE_SEQUENCE != 0 {
+        offset += 4;
+    }
+
+    inner_payload(key, protocol, packet.get(offset..)?)
+}
+
+pub fn decode_vxlan(packet: &[u8]) -> Option<Decapsulated> {
+    if packet.len() < VXLAN_HEADER_LEN || packet[0] & VXLAN_VNI_VALID == 0 {
+        return None;
+    }
+
+    let vni = u32::from_be_bytes([0, packet[4], packet[5], packet[6]]);
+    inner_payload(Some(vni), ETHERTYPE_TEB, &packet[VXLAN_HEADER_LEN..])
+}
+
+pub fn decode_geneve(packet: &[u8]) -> Option<Decapsulated> {
+    if packet.len() < GENEVE_HEADER_LEN {
+        return None;
+    }
+
+    // 2 bits version, 6 bits options length in 4 byte words
+    if packet[0] >> 6 != 0 {
+        return None;
+    }
+
+    let options_len = (packet[0] & 0x3f) as usize * 4;
+    let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+    let vni = u32::from_be_bytes([0, packet[4], packet[5], packet[6]]);
+
+    inner_payload(
+        Some(vni),
+        protocol,
+        packet.get(GENEVE_HEADER_LEN + options_len..)?,
+    )
+}
+
+fn inner_payload(id: Option<u32>, protocol: u16, payload: &[u8]) -> Option<Decapsulated> {
+    let (ether_type, payload) = match protocol {
+        ETHERTYPE_TEB => decode_ethernet(payload)?,
+        _ => (EtherType(protocol), payload),
+    };
+
+    // values below 0x0600 are 802.3 lengths, not ethertypes
+    if ether_type.0 < 0x0600 {
+        return None;
+    }
+
+    Some(Decapsulated {
+        id,
+        ether_type,
+        payload,
+    })
+}
@@ -0,0 +1,599 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::net::IpAddr;
+
+/**
+ * This module reads the parts of DER encoded X.509 certificates
+ * we show for tls connections, see RFC 5280
+ */
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+// context specific tags in TBSCertificate and GeneralName
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const TAG_SAN_DNS: u8 = 0x82;
+const TAG_SAN_IP: u8 = 0x87;
+
+const OID_SUBJECT_ALT_NAME: &str = "2.5.29.17";
+const OID_COMMON_NAME: &str = "2.5.4.3";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    // dns names and ip addresses
+    pub san: Vec<String>,
+    // unix seconds
+    pub not_before: i64,
+    pub not_after: i64,
+    pub key_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_bits: Option<u32>,
+    pub serial: String,
+    pub sha256: String,
+    pub expired: bool,
+    pub not_yet_valid: bool,
+    // issuer and subject are the same name, the signature isn't checked
+    pub self_signed: bool,
+    // only set on the leaf certificate when the client sent an SNI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname_mismatch: Option<bool>,
+    #[serde(skip)]
+    common_name: Option<String>,
+}
+
+impl CertificateInfo {
+    /// Flags certificates outside their validity period at capture time
+    pub fn check_validity(&mut self, now: i64) {
+        self.expired = now > self.not_after;
+        self.not_yet_valid = now < self.not_before;
+    }
+
+    /// Flags a leaf certificate that doesn't cover the requested name,
+    /// the common name is only used without dns SANs
+    pub fn check_hostname(&mut self, sni: &str) {
+        let dns_names: Vec<&String> = self
+            .san
+            .iter()
+            .filter(|name| name.parse::<IpAddr>().is_err())
+            .collect();
+
+        let matched = if dns_names.is_empty() {
+            self.common_name
+                .as_ref()
+                .map_or(false, |name| matches_hostname(name, sni))
+        } else {
+            dns_names.iter().any(|name| matches_hostname(name, sni))
+        };
+
+        self.hostname_mismatch = Some(!matched);
+    }
+}
+
+/// Reads a DER certificate, None if it's malformed
+pub fn parse_certificate(der: &[u8]) -> Option<CertificateInfo> {
+    let (tag, certificate, _) = read_tlv(der)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (tag, tbs, _) = read_tlv(certificate)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (mut tag, mut content, mut rest) = read_tlv(tbs)?;
+    if tag == TAG_VERSION {
+        let next = read_tlv(rest)?;
+        tag = next.0;
+        content = next.1;
+        rest = next.2;
+    }
+
+    expect(tag, TAG_INTEGER)?;
+    let serial = hex(content);
+
+    // signature algorithm, repeated outside the TBSCertificate
+    let (_, _, rest) = read_tlv(rest)?;
+
+    let (tag, issuer, rest) = read_tlv(rest)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (tag, validity, rest) = read_tlv(rest)?;
+    expect(tag, TAG_SEQUENCE)?;
+    let (not_before, validity) = read_time(validity)?;
+    let (not_after, _) = read_time(validity)?;
+
+    let (tag, subject, rest) = read_tlv(rest)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (tag, public_key, mut rest) = read_tlv(rest)?;
+    expect(tag, TAG_SEQUENCE)?;
+    let (key_type, key_bits) = public_key_info(public_key)?;
+
+    // unique ids may come before the extensions
+    let mut san = Vec::new();
+    while !rest.is_empty() {
+        let (tag, content, next) = read_tlv(rest)?;
+        if tag == TAG_EXTENSIONS {
+            san = subject_alt_names(content).unwrap_or_default();
+        }
+        rest = next;
+    }
+
+    Some(CertificateInfo {
+        subject: name_string(subject),
+        issuer: name_string(issuer),
+        san,
+        not_before,
+        not_after,
+        key_type,
+        key_bits,
+        serial,
+        sha256: hex(&Sha256::digest(der)),
+        expired: false,
+        not_yet_valid: false,
+        self_signed: subject == issuer,
+        hostname_mismatch: None,
+        common_name: name_attribute(subject, OID_COMMON_NAME),
+    })
+}
+
+/// Case insensitive, a leading "*." matches exactly one label
+pub fn matches_hostname(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+
+    if pattern.starts_with("*.") {
+        let suffix = &pattern[1..];
+        match hostname.find('.') {
+            Some(dot) => dot > 0 && &hostname[dot..] == suffix,
+            None => false,
+        }
+    } else {
+        pattern == hostname
+    }
+}
+
+// tag, content and what follows, for single byte tags
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.get(0)?;
+    let first = *data.get(1)? as usize;
+
+    let (header_len, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let bytes = data.get(2..2 + count)?;
+        let len = bytes.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        (2 + count, len)
+    };
+
+    let end = header_len.checked_add(len)?;
+    let content = data.get(header_len..end)?;
+
+    Some((tag, content, &data[end..]))
+}
+
+fn expect(tag: u8, expected: u8) -> Option<()> {
+    if tag == expected {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn oid_string(oid: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut value: u64 = 0;
+
+    for byte in oid {
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+
+        if parts.is_empty() {
+            // the first subidentifier packs the first two arcs
+            let first = if value < 80 { value / 40 } else { 2 };
+            parts.push(first);
+            parts.push(value - first * 40);
+        } else {
+            parts.push(value);
+        }
+        value = 0;
+    }
+
+    parts
+        .iter()
+        .map(|part| part.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn attribute_label(oid: &str) -> &str {
+    match oid {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "1.2.840.113549.1.9.1" => "emailAddress",
+        _ => oid,
+    }
+}
+
+// (oid, value) pairs of a Name, in encoded order
+fn name_attributes(name: &[u8]) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = name;
+
+    while let Some((tag, set, next)) = read_tlv(rest) {
+        rest = next;
+        if tag != TAG_SET {
+            continue;
+        }
+
+        let mut entries = set;
+        while let Some((_, entry, next)) = read_tlv(entries) {
+            entries = next;
+
+            if let Some((TAG_OID, oid, value)) = read_tlv(entry) {
+                if let Some((_, value, _)) = read_tlv(value) {
+                    attributes.push((oid_string(oid), String::from_utf8_lossy(value).into_owned()));
+                }
+            }
+        }
+    }
+
+    attributes
+}
+
+fn name_string(name: &[u8]) -> String {
+    name_attributes(name)
+        .iter()
+        .map(|(oid, value)| format!("{}={}", attribute_label(oid), value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn name_attribute(name: &[u8], oid: &str) -> Option<String> {
+    name_attributes(name)
+        .into_iter()
+        .find(|(attribute, _)| attribute == oid)
+        .map(|(_, value)| value)
+}
+
+// UTCTime YYMMDDHHMMSSZ or GeneralizedTime YYYYMMDDHHMMSSZ, as unix seconds
+fn read_time(data: &[u8]) -> Option<(i64, &[u8])> {
+    let (tag, content, rest) = read_tlv(data)?;
+    let text = std::str::from_utf8(content).ok()?;
+
+    let (year, text) = match tag {
+        TAG_UTC_TIME => {
+            let year: i64 = text.get(0..2)?.parse().ok()?;
+            let year = if year >= 50 { 1900 + year } else { 2000 + year };
+            (year, text.get(2..)?)
+        }
+        TAG_GENERALIZED_TIME => (text.get(0..4)?.parse().ok()?, text.get(4..)?),
+        _ => return None,
+    };
+
+    let field = |range: std::ops::Range<usize>| -> Option<i64> { text.get(range)?.parse().ok() };
+    let month = field(0..2)?;
+    let day = field(2..4)?;
+    let hour = field(4..6)?;
+    let minute = field(6..8)?;
+    let second = field(8..10).unwrap_or(0);
+
+    let days = days_from_civil(year, month, day);
+    Some((days * 86400 + hour * 3600 + minute * 60 + second, rest))
+}
+
+// days since 1970-01-01, from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+// SubjectPublicKeyInfo, the algorithm and key size in bits
+fn public_key_info(spki: &[u8]) -> Option<(String, Option<u32>)> {
+    let (tag, algorithm, rest) = read_tlv(spki)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (tag, oid, parameters) = read_tlv(algorithm)?;
+    expect(tag, TAG_OID)?;
+
+    let (tag, key, _) = read_tlv(rest)?;
+    expect(tag, TAG_BIT_STRING)?;
+
+    let info = match oid_string(oid).as_str() {
+        "1.2.840.113549.1.1.1" => ("RSA".to_owned(), rsa_key_bits(key)),
+        "1.2.840.113549.1.1.10" => ("RSA-PSS".to_owned(), rsa_key_bits(key)),
+        "1.2.840.10045.2.1" => {
+            let curve = read_tlv(parameters)
+                .filter(|(tag, _, _)| *tag == TAG_OID)
+                .map(|(_, curve, _)| oid_string(curve));
+
+            match curve.as_ref().map(|curve| curve.as_str()) {
+                Some("1.2.840.10045.3.1.7") => ("EC P-256".to_owned(), Some(256)),
+                Some("1.3.132.0.34") => ("EC P-384".to_owned(), Some(384)),
+                Some("1.3.132.0.35") => ("EC P-521".to_owned(), Some(521)),
+                Some(curve) => (format!("EC {}", curve), None),
+                None => ("EC".to_owned(), None),
+            }
+        }
+        "1.3.101.112" => ("Ed25519".to_owned(), Some(256)),
+        "1.3.101.113" => ("Ed448".to_owned(), Some(456)),
+        "1.2.840.10040.4.1" => ("DSA".to_owned(), None),
+        other => (other.to_owned(), None),
+    };
+
+    Some(info)
+}
+
+// the bit string holds RSAPublicKey ::= SEQUENCE { modulus, publicExponent }
+fn rsa_key_bits(key: &[u8]) -> Option<u32> {
+    // skip the unused bits count
+    let (tag, sequence, _) = read_tlv(key.get(1..)?)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    let (tag, modulus, _) = read_tlv(sequence)?;
+    expect(tag, TAG_INTEGER)?;
+
+    let start = modulus.iter().position(|b| *b != 0)?;
+    let modulus = &modulus[start..];
+
+    Some(modulus.len() as u32 * 8 - modulus[0].leading_zeros())
+}
+
+// Extensions ::= SEQUENCE OF Extension { extnID, critical, extnValue }
+fn subject_alt_names(extensions: &[u8]) -> Option<Vec<String>> {
+    let (tag, mut rest, _) = read_tlv(extensions)?;
+    expect(tag, TAG_SEQUENCE)?;
+
+    while let Some((_, extension, next)) = read_tlv(rest) {
+        rest = next;
+
+        let (tag, oid, mut fields) = read_tlv(extension)?;
+        expect(tag, TAG_OID)?;
+        if oid_string(oid) != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+
+        // skip the critical flag if present
+        let mut value = None;
+        while let Some((tag, content, next)) = read_tlv(fields) {
+            fields = next;
+            if tag == TAG_OCTET_STRING {
+                value = Some(content);
+            }
+        }
+
+        let (tag, mut names, _) = read_tlv(value?)?;
+        expect(tag, TAG_SEQUENCE)?;
+
+        let mut san = Vec::new();
+        while let Some((tag, name, next)) = read_tlv(names) {
+            names = next;
+
+            match (tag, name.len()) {
+                (TAG_SAN_DNS, _) => san.push(String::from_utf8_lossy(name).into_owned()),
+                (TAG_SAN_IP, 4) => {
+                    san.push(IpAddr::from([name[0], name[1], name[2], name[3]]).to_string())
+                }
+                (TAG_SAN_IP, 16) => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(name);
+                    san.push(IpAddr::from(octets).to_string());
+                }
+                _ => {}
+            }
+        }
+
+        return Some(san);
+    }
+
+    Some(Vec::new())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![tag];
+        match content.len() {
+            len if len < 0x80 => bytes.push(len as u8),
+            len if len < 0x100 => bytes.extend_from_slice(&[0x81, len as u8]),
+            len => bytes.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        bytes.extend_from_slice(content);
+        bytes
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let mut attribute = tlv(TAG_OID, &[0x55, 0x04, 0x03]);
+        attribute.extend(tlv(0x0c, common_name.as_bytes()));
+        tlv(TAG_SEQUENCE, &tlv(TAG_SET, &tlv(TAG_SEQUENCE, &attribute)))
+    }
+
+    fn subject_alt_name_extension(names: &[u8]) -> Vec<u8> {
+        let mut extension = tlv(TAG_OID, &[0x55, 0x1d, 0x11]);
+        extension.extend(tlv(TAG_OCTET_STRING, names));
+        tlv(TAG_SEQUENCE, &tlv(TAG_SEQUENCE, &extension))
+    }
+
+    // an ecdsa P-256 leaf for example.com, issued by Example CA
+    fn certificate(extensions: &[u8]) -> Vec<u8> {
+        let mut names = tlv(TAG_SAN_DNS, b"example.com");
+        names.extend(tlv(TAG_SAN_DNS, b"*.example.com"));
+        names.extend(tlv(TAG_SAN_IP, &[192, 0, 2, 1]));
+
+        let mut validity = tlv(TAG_UTC_TIME, b"240101000000Z");
+        validity.extend(tlv(TAG_GENERALIZED_TIME, b"20340101000000Z"));
+
+        // ecPublicKey on prime256v1
+        let mut algorithm = tlv(TAG_OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]);
+        algorithm.extend(tlv(
+            TAG_OID,
+            &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07],
+        ));
+        let mut spki = tlv(TAG_SEQUENCE, &algorithm);
+        let mut key = vec![0, 4];
+        key.extend_from_slice(&[0x11; 64]);
+        spki.extend(tlv(TAG_BIT_STRING, &key));
+
+        // ecdsa-with-SHA256
+        let signature_algorithm = tlv(
+            TAG_SEQUENCE,
+            &tlv(TAG_OID, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+        );
+
+        let mut tbs = tlv(TAG_VERSION, &tlv(TAG_INTEGER, &[2]));
+        tbs.extend(tlv(TAG_INTEGER, &[0x01, 0x23]));
+        tbs.extend(&signature_algorithm);
+        tbs.extend(name("Example CA"));
+        tbs.extend(tlv(TAG_SEQUENCE, &validity));
+        tbs.extend(name("example.com"));
+        tbs.extend(tlv(TAG_SEQUENCE, &spki));
+        if extensions.is_empty() {
+            tbs.extend(tlv(
+                TAG_EXTENSIONS,
+                &subject_alt_name_extension(&tlv(TAG_SEQUENCE, &names)),
+            ));
+        } else {
+            tbs.extend(tlv(TAG_EXTENSIONS, extensions));
+        }
+
+        let mut certificate = tlv(TAG_SEQUENCE, &tbs);
+        certificate.extend(signature_algorithm);
+        certificate.extend(tlv(TAG_BIT_STRING, &[0, 0x30, 0x00]));
+        tlv(TAG_SEQUENCE, &certificate)
+    }
+
+    #[test]
+    fn leaf_certificate() {
+        let info = parse_certificate(&certificate(&[])).unwrap();
+
+        assert_eq!(info.subject, "CN=example.com");
+        assert_eq!(info.issuer, "CN=Example CA");
+        assert_eq!(info.san, vec!["example.com", "*.example.com", "192.0.2.1"]);
+        assert_eq!(info.not_before, 1704067200);
+        assert_eq!(info.not_after, 2019686400);
+        assert_eq!(info.key_type, "EC P-256");
+        assert_eq!(info.key_bits, Some(256));
+        assert_eq!(info.serial, "0123");
+        assert!(!info.self_signed);
+        assert_eq!(info.common_name.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn validity_and_hostname() {
+        let mut info = parse_certificate(&certificate(&[])).unwrap();
+
+        info.check_validity(2019686401);
+        assert!(info.expired && !info.not_yet_valid);
+        info.check_validity(1704067199);
+        assert!(!info.expired && info.not_yet_valid);
+
+        info.check_hostname("www.example.com");
+        assert_eq!(info.hostname_mismatch, Some(false));
+        info.check_hostname("a.b.example.com");
+        assert_eq!(info.hostname_mismatch, Some(true));
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(matches_hostname("Example.COM.", "example.com"));
+        assert!(matches_hostname("*.example.com", "www.example.com"));
+        assert!(!matches_hostname("*.example.com", "example.com"));
+        assert!(!matches_hostname("*.example.com", ".example.com"));
+        assert!(!matches_hostname("*.example.com", "www.example.org"));
+    }
+
+    #[test]
+    fn truncated_lengths() {
+        // content shorter than its length
+        assert_eq!(read_tlv(&[TAG_SEQUENCE, 0x05, 1, 2]), None);
+        // long form length bytes cut short
+        assert_eq!(read_tlv(&[TAG_SEQUENCE, 0x82, 0x01]), None);
+        assert_eq!(read_tlv(&[TAG_SEQUENCE]), None);
+        assert_eq!(read_tlv(&[]), None);
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        // indefinite lengths aren't DER
+        assert_eq!(read_tlv(&[TAG_SEQUENCE, 0x80, 0, 0]), None);
+        // more length bytes than fit
+        assert_eq!(read_tlv(&[TAG_SEQUENCE, 0x85, 0, 0, 0, 0, 1, 0]), None);
+        assert_eq!(
+            read_tlv(&[TAG_SEQUENCE, 0x84, 0xff, 0xff, 0xff, 0xff, 0]),
+            None
+        );
+        // long form is fine when it fits
+        assert_eq!(
+            read_tlv(&[TAG_SEQUENCE, 0x81, 0x01, 0xaa, 0xbb]),
+            Some((TAG_SEQUENCE, &[0xaa][..], &[0xbb][..]))
+        );
+    }
+
+    #[test]
+    fn truncated_certificate() {
+        let der = certificate(&[]);
+        for len in 0..der.len() {
+            assert!(parse_certificate(&der[..len]).is_none(), "{} bytes", len);
+        }
+
+        // the outer length claims a byte more than there is
+        let mut der = der;
+        der[3] += 1;
+        assert!(parse_certificate(&der).is_none());
+    }
+
+    #[test]
+    fn oversized_subject_alt_names() {
+        // the names sequence claims more than the extension holds
+        let mut names = tlv(TAG_SEQUENCE, &tlv(TAG_SAN_DNS, b"example.com"));
+        names[1] += 1;
+
+        let info = parse_certificate(&certificate(&subject_alt_name_extension(&names))).unwrap();
+        assert!(info.san.is_empty());
+
+        // so the common name is used instead
+        let mut info = info;
+        info.check_hostname("example.com");
+        assert_eq!(info.hostname_mismatch, Some(false));
+    }
+
+    #[test]
+    fn times() {
+        let time = |tag, text: &[u8]| read_time(&tlv(tag, text)).map(|(time, _)| time);
+
+        assert_eq!(time(TAG_UTC_TIME, b"491231235959Z"), Some(2524607999));
+        assert_eq!(time(TAG_UTC_TIME, b"500101000000Z"), Some(-631152000));
+        assert_eq!(time(TAG_GENERALIZED_TIME, b"19700101000000Z"), Some(0));
+        assert_eq!(time(TAG_UTC_TIME, b"2401"), None);
+        assert_eq!(time(TAG_OCTET_STRING, b"240101000000Z"), None);
+    }
+}